@@ -14,8 +14,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Opt::from_args();
     let host = SocketAddr::from_str(cli.host.as_str())?;
     println!("Connecting to server {}", cli.host);
-    let (uplink_sender, mut downlink_request_receiver, udp_runtime) =
-        UdpRuntime::new(mac_address, host).await?;
+    let (uplink_sender, mut downlink_request_receiver, udp_runtime) = match &cli.down_host {
+        Some(down_host) => {
+            println!("Exchanging downlinks with {down_host}");
+            let down_host = SocketAddr::from_str(down_host)?;
+            UdpRuntime::new_with_up_down(mac_address, host, down_host).await?
+        }
+        None => UdpRuntime::new(mac_address, host).await?,
+    };
 
     let udp_runtime_task = tokio::spawn(udp_runtime.run(shutdown_signal));
 
@@ -23,10 +29,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         loop {
             println!("Sending a random uplink");
 
-            uplink_sender
+            let push_ack = uplink_sender
                 .send(semtech_udp::push_data::Packet::random())
                 .await
                 .unwrap();
+            match push_ack.await {
+                Ok(rtt) => println!("Uplink acknowledged after {rtt:?}"),
+                Err(e) => println!("Uplink not acknowledged: {e}"),
+            }
             sleep(Duration::from_secs(5)).await;
         }
     });
@@ -43,6 +53,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Event::UnableToParseUdpFrame(parse_error, _buffer) => {
                 println!("Error parsing UDP frame {parse_error}")
             }
+            Event::ServerAddressChanged {
+                host,
+                previous,
+                current,
+            } => {
+                println!("Server {host} moved from {previous} to {current}")
+            }
         }
     }
     shutdown_trigger.trigger();
@@ -57,4 +74,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 pub struct Opt {
     #[structopt(short, long, default_value = "127.0.0.1:1680")]
     pub host: String,
+    /// Server address for PULL_DATA and downlinks, when distinct from the uplink one
+    #[structopt(long)]
+    pub down_host: Option<String>,
 }
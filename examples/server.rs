@@ -17,7 +17,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Ready for clients");
     loop {
         println!("Waiting for event");
-        match udp_runtime.recv().await {
+        let Some(event) = udp_runtime.recv().await else {
+            println!("Server runtime stopped");
+            return Ok(());
+        };
+        match event {
             Event::UnableToParseUdpFrame(error, buf) => {
                 println!("Semtech UDP Parsing Error: {error}");
                 println!("UDP data: {buf:?}");
@@ -77,6 +81,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Event::NoClientWithMac(_packet, mac) => {
                 println!("Tried to send to client with unknown MAC: {mac:?}")
             }
+            Event::DedupedPacketReceived(packet) => {
+                println!("Deduplicated packet: {:?}", packet.best());
+            }
+            Event::LateDuplicate(rxpk, gateway_mac) => {
+                println!("Late duplicate from {gateway_mac}: {rxpk:?}");
+            }
+            Event::AckTimeout((mac, token)) => {
+                println!("Downlink {token} to {mac} was never acknowledged");
+            }
+            Event::MismatchedTxAck(txack, expected) => {
+                println!(
+                    "TX_ACK from {} for a downlink sent to {expected}",
+                    txack.gateway_mac
+                );
+            }
+            Event::UnmatchedTxAck(txack, late) => {
+                println!("Unmatched TX_ACK {txack:?}, sent {late:?} ago");
+            }
+            Event::UnknownGateway((mac, addr)) => {
+                println!("Rejected unknown gateway {mac} at {addr}");
+            }
+            Event::MacConflict((mac, addrs)) => {
+                println!("Gateway {mac} claimed from {addrs:?}");
+            }
         }
     }
 }
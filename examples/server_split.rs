@@ -77,7 +77,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Ready for clients");
     loop {
-        match client_rx.recv().await {
+        let Some(event) = client_rx.recv().await else {
+            println!("Server runtime stopped");
+            return Ok(());
+        };
+        match event {
             Event::UnableToParseUdpFrame(error, buf) => {
                 println!("Semtech UDP Parsing Error: {error}");
                 println!("UDP data: {buf:?}");
@@ -106,6 +110,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Event::ClientDisconnected((mac, addr)) => {
                 println!("Client disconnected: {mac}, {addr}");
             }
+            Event::DedupedPacketReceived(packet) => {
+                println!("Deduplicated packet: {:?}", packet.best());
+            }
+            Event::LateDuplicate(rxpk, addr) => {
+                println!("Late duplicate from {addr}: {rxpk:?}");
+            }
+            Event::AckTimeout((mac, token)) => {
+                println!("Downlink {token} to {mac} was never acknowledged");
+            }
+            Event::MismatchedTxAck(txack, expected) => {
+                println!(
+                    "TX_ACK from {} for a downlink sent to {expected}",
+                    txack.gateway_mac
+                );
+            }
+            Event::UnmatchedTxAck(txack, late) => {
+                println!("Unmatched TX_ACK {txack:?}, sent {late:?} ago");
+            }
+            Event::UnknownGateway((mac, addr)) => {
+                println!("Rejected unknown gateway {mac} at {addr}");
+            }
+            Event::MacConflict((mac, addrs)) => {
+                println!("Gateway {mac} claimed from {addrs:?}");
+            }
         }
     }
 }
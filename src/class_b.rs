@@ -0,0 +1,382 @@
+/*
+   Helpers for scheduling LoRaWAN Class B downlinks (LoRaWAN 1.0.4 / 1.1, section 13 and
+   RP002 "Beacon" sections). Beacons and ping slots are aligned to GPS time, so every TxPk
+   built here is scheduled with `tmms` and may be handed directly to the server runtime.
+*/
+use crate::{
+    pull_resp::{PhyData, Time, TxPk},
+    Bandwidth, CodingRate, DataRate, Modulation, SpreadingFactor,
+};
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Beacons are emitted every 128 seconds of GPS time
+pub const BEACON_PERIOD_SECS: u64 = 128;
+/// Time reserved after the beacon start before the first ping slot opens
+pub const BEACON_RESERVED_MS: u64 = 2_120;
+/// Length of a single ping slot
+pub const PING_SLOT_LEN_MS: u64 = 30;
+/// Number of ping slots in one beacon window
+pub const PING_SLOT_COUNT: u16 = 4096;
+
+/// Seconds between the UNIX epoch and the GPS epoch (1980-01-06T00:00:00Z)
+pub const GPS_EPOCH_UNIX_SECS: u64 = 315_964_800;
+/// Leap seconds between UTC and GPS time as of 2017-01-01
+pub const GPS_LEAP_SECS: u64 = 18;
+
+const BEACON_PREAMBLE: u64 = 10;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("ping slot periodicity must be between 0 and 7, got {0}")]
+    InvalidPeriodicity(u8),
+    #[error("system time is before the GPS epoch")]
+    BeforeGpsEpoch,
+}
+
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Converts a system time into GPS time, using the supplied leap-second offset
+pub fn gps_time(time: SystemTime, leap_secs: u64) -> Result<Duration> {
+    let unix = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::BeforeGpsEpoch)?;
+    unix.checked_sub(Duration::from_secs(GPS_EPOCH_UNIX_SECS))
+        .map(|gps| gps + Duration::from_secs(leap_secs))
+        .ok_or(Error::BeforeGpsEpoch)
+}
+
+/// Current GPS time using `GPS_LEAP_SECS`
+pub fn gps_time_now() -> Result<Duration> {
+    gps_time(SystemTime::now(), GPS_LEAP_SECS)
+}
+
+/// GPS seconds of the most recent beacon at or before `gps_time`
+pub fn beacon_time(gps_time: Duration) -> u64 {
+    let secs = gps_time.as_secs();
+    secs - secs % BEACON_PERIOD_SECS
+}
+
+/// GPS seconds of the first beacon strictly after `gps_time`
+pub fn next_beacon_time(gps_time: Duration) -> u64 {
+    beacon_time(gps_time) + BEACON_PERIOD_SECS
+}
+
+/// Regional beacon and default ping-slot parameters, as given in RP002
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    EU868,
+    US915,
+    AU915,
+    AS923,
+}
+
+impl Region {
+    // sizes of the two RFU fields surrounding the beacon's first CRC
+    fn rfu_lens(&self) -> (usize, usize) {
+        match self {
+            Region::EU868 | Region::AS923 => (2, 0),
+            Region::US915 | Region::AU915 => (5, 3),
+        }
+    }
+
+    /// Size of the beacon PHY payload in bytes
+    pub fn beacon_len(&self) -> usize {
+        let (rfu1, rfu2) = self.rfu_lens();
+        rfu1 + 4 + 2 + 7 + rfu2 + 2
+    }
+
+    pub fn beacon_datarate(&self) -> DataRate {
+        match self {
+            Region::EU868 | Region::AS923 => DataRate::new(SpreadingFactor::_9, Bandwidth::_125KHz),
+            Region::US915 | Region::AU915 => {
+                DataRate::new(SpreadingFactor::_12, Bandwidth::_500KHz)
+            }
+        }
+    }
+
+    /// Default ping-slot datarate; the network may override it with PingSlotChannelReq
+    pub fn ping_slot_datarate(&self) -> DataRate {
+        self.beacon_datarate()
+    }
+
+    /// Beacon frequency in MHz. US915 and AU915 hop over 8 channels every beacon period.
+    pub fn beacon_frequency(&self, beacon_time: u64) -> f64 {
+        match self {
+            Region::EU868 => 869.525,
+            Region::AS923 => 923.4,
+            Region::US915 | Region::AU915 => {
+                hopping_frequency(beacon_time / BEACON_PERIOD_SECS % 8)
+            }
+        }
+    }
+
+    /// Default ping-slot frequency in MHz. US915 and AU915 hop based on DevAddr.
+    pub fn ping_slot_frequency(&self, beacon_time: u64, dev_addr: u32) -> f64 {
+        match self {
+            Region::EU868 | Region::AS923 => self.beacon_frequency(beacon_time),
+            Region::US915 | Region::AU915 => {
+                hopping_frequency((beacon_time / BEACON_PERIOD_SECS + dev_addr as u64) % 8)
+            }
+        }
+    }
+}
+
+fn hopping_frequency(channel: u64) -> f64 {
+    // 923.3 MHz + channel * 600 kHz, computed in kHz to avoid float drift
+    (923_300 + channel * 600) as f64 / 1000.0
+}
+
+/// The gateway-specific part of a beacon. Only the GPS coordinate variants
+/// (InfoDesc 0, 1 and 2) are defined by the specification.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GatewaySpecific {
+    pub info_desc: u8,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl GatewaySpecific {
+    pub fn new(latitude: f64, longitude: f64) -> GatewaySpecific {
+        GatewaySpecific {
+            info_desc: 0,
+            latitude,
+            longitude,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.info_desc);
+        out.extend_from_slice(&encode_coordinate(self.latitude, 90.0));
+        out.extend_from_slice(&encode_coordinate(self.longitude, 180.0));
+    }
+}
+
+// coordinates are 24-bit two's complement, little endian, scaled to 2^23 / range
+fn encode_coordinate(value: f64, range: f64) -> [u8; 3] {
+    let scaled = (value / range * (1 << 23) as f64) as i32;
+    let scaled = scaled.clamp(-(1 << 23), (1 << 23) - 1);
+    let bytes = scaled.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+/// CRC-16 used by beacons (polynomial 0x1021, initial value 0), as implemented by the
+/// Semtech packet forwarder
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Builds the beacon PHY payload for `beacon_time` (GPS seconds)
+pub fn beacon_payload(region: Region, beacon_time: u64, gw_specific: &GatewaySpecific) -> Vec<u8> {
+    let (rfu1, rfu2) = region.rfu_lens();
+    let mut payload = Vec::with_capacity(region.beacon_len());
+    payload.resize(rfu1, 0);
+    // the time field is the GPS time in seconds modulo 2^32
+    payload.extend_from_slice(&(beacon_time as u32).to_le_bytes());
+    let crc = crc16(&payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+
+    let gw_start = payload.len();
+    gw_specific.write(&mut payload);
+    payload.resize(payload.len() + rfu2, 0);
+    let crc = crc16(&payload[gw_start..]);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+/// Builds the TxPk for the beacon emitted at `beacon_time` (GPS seconds)
+pub fn beacon_txpk(
+    region: Region,
+    beacon_time: u64,
+    gw_specific: &GatewaySpecific,
+    rfch: u64,
+    powe: u64,
+) -> TxPk {
+    TxPk {
+        time: Time::by_tmms(beacon_time * 1000),
+        freq: region.beacon_frequency(beacon_time),
+        rfch,
+        powe,
+        modu: Modulation::LORA,
+        datr: region.beacon_datarate(),
+        codr: Some(CodingRate::_4_5),
+        fdev: None,
+        // beacons are sent with non-inverted polarity and no PHY CRC
+        ipol: false,
+        prea: Some(BEACON_PREAMBLE),
+        data: PhyData::new(beacon_payload(region, beacon_time, gw_specific)),
+        ncrc: Some(true),
+    }
+}
+
+/// Number of ping slots per beacon period for the given periodicity (0..=7)
+pub fn ping_nb(periodicity: u8) -> Result<u16> {
+    if periodicity > 7 {
+        Err(Error::InvalidPeriodicity(periodicity))
+    } else {
+        Ok(1 << (7 - periodicity))
+    }
+}
+
+/// Number of slots between two consecutive ping slots for the given periodicity
+pub fn ping_period(periodicity: u8) -> Result<u16> {
+    Ok(PING_SLOT_COUNT / ping_nb(periodicity)?)
+}
+
+/// Computes the pseudo-random ping offset for a device in the beacon window starting at
+/// `beacon_time`: `Rand = aes128_encrypt(0x00..00, BeaconTime | DevAddr | pad16)` and
+/// `pingOffset = (Rand[0] + Rand[1] * 256) mod pingPeriod`
+pub fn ping_offset(beacon_time: u64, dev_addr: u32, periodicity: u8) -> Result<u16> {
+    let period = ping_period(periodicity)?;
+    let mut block = [0u8; 16];
+    block[..4].copy_from_slice(&(beacon_time as u32).to_le_bytes());
+    block[4..8].copy_from_slice(&dev_addr.to_le_bytes());
+
+    let cipher = Aes128::new(&GenericArray::from([0u8; 16]));
+    let mut block = GenericArray::from(block);
+    cipher.encrypt_block(&mut block);
+
+    Ok((block[0] as u16 + block[1] as u16 * 256) % period)
+}
+
+/// GPS times in milliseconds at which the device's ping slots open during the beacon
+/// window starting at `beacon_time`
+pub fn ping_slot_times(beacon_time: u64, dev_addr: u32, periodicity: u8) -> Result<Vec<u64>> {
+    let offset = ping_offset(beacon_time, dev_addr, periodicity)? as u64;
+    let period = ping_period(periodicity)? as u64;
+    let window_start = beacon_time * 1000 + BEACON_RESERVED_MS;
+    Ok((0..ping_nb(periodicity)? as u64)
+        .map(|n| window_start + (offset + n * period) * PING_SLOT_LEN_MS)
+        .collect())
+}
+
+/// First ping slot for the device opening at or after `gps_time`
+pub fn next_ping_slot(gps_time: Duration, dev_addr: u32, periodicity: u8) -> Result<u64> {
+    let now_ms = gps_time.as_millis() as u64;
+    let mut beacon = beacon_time(gps_time);
+    loop {
+        if let Some(slot) = ping_slot_times(beacon, dev_addr, periodicity)?
+            .into_iter()
+            .find(|slot| *slot >= now_ms)
+        {
+            return Ok(slot);
+        }
+        beacon += BEACON_PERIOD_SECS;
+    }
+}
+
+/// Builds a Class B downlink scheduled at `slot_ms` (GPS milliseconds) on the region's
+/// default ping-slot channel and datarate
+pub fn ping_slot_txpk(
+    region: Region,
+    slot_ms: u64,
+    dev_addr: u32,
+    rfch: u64,
+    powe: u64,
+    data: Vec<u8>,
+) -> TxPk {
+    let beacon_time = beacon_time(Duration::from_millis(slot_ms));
+    TxPk {
+        time: Time::by_tmms(slot_ms),
+        freq: region.ping_slot_frequency(beacon_time, dev_addr),
+        rfch,
+        powe,
+        modu: Modulation::LORA,
+        datr: region.ping_slot_datarate(),
+        codr: Some(CodingRate::_4_5),
+        fdev: None,
+        ipol: true,
+        prea: None,
+        data: PhyData::new(data),
+        ncrc: Some(true),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn beacon_time_alignment() {
+        let gps = Duration::from_millis(1_000_000_123);
+        assert_eq!(beacon_time(gps), 999_936);
+        assert_eq!(next_beacon_time(gps), 1_000_064);
+        assert_eq!(beacon_time(Duration::from_secs(999_936)), 999_936);
+    }
+
+    #[test]
+    fn beacon_payload_layout() {
+        let gw = GatewaySpecific::new(45.0, -90.0);
+        for region in [Region::EU868, Region::US915] {
+            let (rfu1, rfu2) = region.rfu_lens();
+            let payload = beacon_payload(region, 0x1234_5678, &gw);
+            assert_eq!(payload.len(), region.beacon_len());
+            assert_eq!(&payload[rfu1..rfu1 + 4], &[0x78, 0x56, 0x34, 0x12]);
+
+            let crc1 = crc16(&payload[..rfu1 + 4]).to_le_bytes();
+            assert_eq!(&payload[rfu1 + 4..rfu1 + 6], &crc1);
+
+            let gw_start = rfu1 + 6;
+            // half of the latitude range and half of the negative longitude range
+            assert_eq!(
+                &payload[gw_start..gw_start + 7],
+                &[0, 0, 0, 0x40, 0, 0, 0xC0]
+            );
+            let crc2 = crc16(&payload[gw_start..gw_start + 7 + rfu2]).to_le_bytes();
+            assert_eq!(&payload[payload.len() - 2..], &crc2);
+        }
+    }
+
+    #[test]
+    fn beacon_txpk_uses_gps_time() {
+        let txpk = beacon_txpk(Region::US915, 1_280, &GatewaySpecific::new(0.0, 0.0), 0, 27);
+        assert_eq!(txpk.time.tmms(), Some(1_280_000));
+        // 1280 / 128 = 10, 10 mod 8 = 2
+        assert_eq!(txpk.freq, 924.5);
+        assert_eq!(txpk.data.len(), 23);
+    }
+
+    #[test]
+    fn ping_slots_fall_in_beacon_window() {
+        assert!(ping_offset(0, 0, 8).is_err());
+        for periodicity in 0..=7 {
+            let beacon = 1_000_064;
+            let slots = ping_slot_times(beacon, 0x2601_1BDA, periodicity).unwrap();
+            assert_eq!(slots.len(), ping_nb(periodicity).unwrap() as usize);
+            let window_start = beacon * 1000 + BEACON_RESERVED_MS;
+            let window_end = window_start + PING_SLOT_COUNT as u64 * PING_SLOT_LEN_MS;
+            for slot in slots {
+                assert!(slot >= window_start && slot < window_end);
+            }
+        }
+    }
+
+    #[test]
+    fn next_ping_slot_rolls_to_next_beacon() {
+        let beacon = 1_000_064;
+        let last = *ping_slot_times(beacon, 1, 7).unwrap().last().unwrap();
+        let next = next_ping_slot(Duration::from_millis(last + 1), 1, 7).unwrap();
+        let expected = ping_slot_times(beacon + BEACON_PERIOD_SECS, 1, 7).unwrap()[0];
+        assert_eq!(next, expected);
+    }
+}
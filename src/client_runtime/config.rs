@@ -0,0 +1,149 @@
+use super::{
+    Error, LocationProvider, PushAckPolicy, Result, TemperatureProvider, DEFAULT_MISSED_PULL_ACKS,
+};
+use crate::duration::{millis, option_millis};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+const DEFAULT_KEEPALIVE_INTERVAL: u64 = 10;
+const DEFAULT_RECV_BUFFER_SIZE: usize = 1024;
+
+// Durations are (de)serialized as milliseconds, with the field names suffixed by `_ms`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// How often PULL_DATA are sent to keep the downlink path open
+    #[serde(rename = "keepalive_interval_ms", with = "millis")]
+    pub keepalive_interval: Duration,
+    /// PULL_DATA left without PULL_ACK before `Event::LostConnection`; 0 never loses the
+    /// connection on missed PULL_ACKs, only on failed sends
+    pub missed_pull_acks: u32,
+    /// Largest UDP datagram received from the server
+    pub recv_buffer_size: usize,
+    /// How often the server hosts are resolved again, reconnecting the sockets whose address
+    /// changed; `None` resolves them once. Only applies to `UdpRuntime::new_with_config`
+    #[serde(rename = "resolve_interval_ms", with = "option_millis")]
+    pub resolve_interval: Option<Duration>,
+    /// How long a PUSH_DATA waits for its PUSH_ACK, and how often it is sent again meanwhile
+    pub push_ack_policy: PushAckPolicy,
+    /// How often a stat is sent; `None` sends none
+    #[serde(rename = "stat_interval_ms", with = "option_millis")]
+    pub stat_interval: Option<Duration>,
+    /// Location and temperature reported in the stats
+    #[serde(skip)]
+    pub location: Option<Arc<dyn LocationProvider>>,
+    #[serde(skip)]
+    pub temperature: Option<Arc<dyn TemperatureProvider>>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            keepalive_interval: Duration::from_secs(DEFAULT_KEEPALIVE_INTERVAL),
+            missed_pull_acks: DEFAULT_MISSED_PULL_ACKS,
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            resolve_interval: None,
+            push_ack_policy: PushAckPolicy::default(),
+            stat_interval: None,
+            location: None,
+            temperature: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    // rejects values the runtime cannot run with, such as a zero keepalive interval
+    pub fn validate(&self) -> Result {
+        let invalid = |reason| Err(Error::InvalidConfig(reason));
+        if self.keepalive_interval.is_zero() {
+            return invalid("keepalive_interval_ms must be greater than 0");
+        }
+        if self
+            .stat_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return invalid("stat_interval_ms must be greater than 0");
+        }
+        if self
+            .resolve_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return invalid("resolve_interval_ms must be greater than 0");
+        }
+        if self.recv_buffer_size == 0 {
+            return invalid("recv_buffer_size must be greater than 0");
+        }
+        Ok(())
+    }
+
+    pub fn keepalive(mut self, interval: Duration, missed_pull_acks: u32) -> Self {
+        self.keepalive_interval = interval;
+        self.missed_pull_acks = missed_pull_acks;
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    pub fn resolve_interval(mut self, interval: Option<Duration>) -> Self {
+        self.resolve_interval = interval;
+        self
+    }
+
+    // unacknowledged PUSH_DATA are not retransmitted unless the policy allows it
+    pub fn push_ack_policy(mut self, policy: PushAckPolicy) -> Self {
+        self.push_ack_policy = policy;
+        self
+    }
+
+    // sends a stat every interval, like the stat_interval of the reference forwarder;
+    // the counters cover the uplinks sent and downlinks requested and acked through the runtime
+    pub fn report_stats(
+        mut self,
+        interval: Duration,
+        location: Option<Arc<dyn LocationProvider>>,
+        temperature: Option<Arc<dyn TemperatureProvider>>,
+    ) -> Self {
+        self.stat_interval = Some(interval);
+        self.location = location;
+        self.temperature = temperature;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let json = "{\"keepalive_interval_ms\":5000,\"resolve_interval_ms\":300000,\"push_ack_policy\":{\"retransmissions\":2},\"stat_interval_ms\":30000}";
+        let config: ClientConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.keepalive_interval, Duration::from_secs(5));
+        assert_eq!(config.resolve_interval, Some(Duration::from_secs(300)));
+        assert_eq!(config.push_ack_policy.retransmissions, 2);
+        assert_eq!(
+            config.push_ack_policy.timeout,
+            PushAckPolicy::default().timeout
+        );
+        assert_eq!(config.stat_interval, Some(Duration::from_secs(30)));
+        assert_eq!(config.recv_buffer_size, DEFAULT_RECV_BUFFER_SIZE);
+        assert_eq!(config.missed_pull_acks, DEFAULT_MISSED_PULL_ACKS);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert!(ClientConfig::default().validate().is_ok());
+        for json in [
+            "{\"keepalive_interval_ms\":0}",
+            "{\"stat_interval_ms\":0}",
+            "{\"resolve_interval_ms\":0}",
+            "{\"recv_buffer_size\":0}",
+        ] {
+            let config: ClientConfig = serde_json::from_str(json).unwrap();
+            assert!(matches!(config.validate(), Err(Error::InvalidConfig(_))));
+        }
+    }
+}
@@ -6,7 +6,7 @@ pub enum Error {
     #[error("semtech udp error: {0}")]
     SemtechUdp(#[from] crate::packet::Error),
     #[error("tokio::mpsc send error: {0}")]
-    SendError(#[from] mpsc::error::SendError<super::TxMessage>),
+    SendError(#[from] Box<mpsc::error::SendError<super::TxMessage>>),
     #[error("Error binding: {io_error}")]
     Binding { io_error: std::io::Error },
     #[error("Error connecting: {io_error}")]
@@ -14,5 +14,23 @@ pub enum Error {
     #[error("Join error: {0}")]
     Join(#[from] tokio::task::JoinError),
     #[error("Error sending downlink request to client: {0}")]
-    SendingClient(#[from] mpsc::error::SendError<super::Event>),
+    SendingClient(#[from] Box<mpsc::error::SendError<super::Event>>),
+    #[error("PUSH_DATA was not acknowledged")]
+    PushAckTimeout,
+    #[error("Client runtime has stopped")]
+    Shutdown,
+    #[error("Invalid client config: {0}")]
+    InvalidConfig(&'static str),
+}
+
+impl From<mpsc::error::SendError<super::TxMessage>> for Error {
+    fn from(err: mpsc::error::SendError<super::TxMessage>) -> Error {
+        Error::SendError(err.into())
+    }
+}
+
+impl From<mpsc::error::SendError<super::Event>> for Error {
+    fn from(err: mpsc::error::SendError<super::Event>) -> Error {
+        Error::SendingClient(err.into())
+    }
 }
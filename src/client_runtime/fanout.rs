@@ -0,0 +1,309 @@
+/*
+   Feeds several network servers from one gateway, like the `servers` list of the reference
+   forwarder: one UdpRuntime per server, each uplink sent to every server whose filter
+   accepts it, and their events merged into one stream tagged with the server's index.
+*/
+use super::{ClientConfig, ClientTx, Error, Event, PushAck, Result, UdpRuntime};
+use crate::push_data::{self, RxPk, CRC};
+use crate::MacAddress;
+use std::{fmt, sync::Arc};
+use tokio::{
+    sync::mpsc::{self, Receiver},
+    task::JoinSet,
+};
+
+pub trait UplinkFilter: fmt::Debug + Send + Sync {
+    fn forward(&self, rxpk: &RxPk) -> bool;
+}
+
+/// Forwards uplinks by CRC status, like the `forward_crc_*` options of the reference forwarder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrcFilter {
+    pub valid: bool,
+    pub error: bool,
+    pub disabled: bool,
+}
+
+impl Default for CrcFilter {
+    fn default() -> Self {
+        CrcFilter {
+            valid: true,
+            error: false,
+            disabled: false,
+        }
+    }
+}
+
+impl UplinkFilter for CrcFilter {
+    fn forward(&self, rxpk: &RxPk) -> bool {
+        match rxpk.crc_status() {
+            CRC::OK => self.valid,
+            CRC::Fail => self.error,
+            CRC::Disabled => self.disabled,
+        }
+    }
+}
+
+/// A network server fed by the FanoutRuntime
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host: String,
+    down_host: Option<String>,
+    config: ClientConfig,
+    uplink_filter: Option<Arc<dyn UplinkFilter>>,
+    downlinks: bool,
+}
+
+impl Upstream {
+    /// `host` is a "name:port" string; uplinks are not filtered and downlinks are allowed
+    pub fn new(host: impl Into<String>) -> Self {
+        Upstream {
+            host: host.into(),
+            down_host: None,
+            config: ClientConfig::default(),
+            uplink_filter: None,
+            downlinks: true,
+        }
+    }
+
+    /// for a server with distinct up and down ports
+    pub fn down_host(mut self, down_host: impl Into<String>) -> Self {
+        self.down_host = Some(down_host.into());
+        self
+    }
+
+    /// keepalive, buffer size, host re-resolution, PUSH_ACK policy and stats of this server's
+    /// runtime
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn uplink_filter(mut self, filter: impl UplinkFilter + 'static) -> Self {
+        self.uplink_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// downlink requests of a server which may not transmit are dropped without TX_ACK
+    pub fn downlinks(mut self, allowed: bool) -> Self {
+        self.downlinks = allowed;
+        self
+    }
+}
+
+// events of every server, with the index of the server in the list given to FanoutRuntime::new
+pub type FanoutRx = Receiver<(usize, Event)>;
+
+#[derive(Debug, Clone)]
+pub struct FanoutTx {
+    servers: Vec<(ClientTx, Option<Arc<dyn UplinkFilter>>)>,
+}
+
+impl FanoutTx {
+    // sends the uplink to every server whose filter accepts some of it, and returns the
+    // PUSH_ACK of each, or why the uplink could not be queued, with the server's index;
+    // the uplink is queued to every server at once, so a full queue only delays its own server
+    pub async fn send(&self, push_data: push_data::Packet) -> Vec<(usize, Result<PushAck>)> {
+        let mut tasks = JoinSet::new();
+        let mut queued = Vec::new();
+        for (server, (client_tx, filter)) in self.servers.iter().enumerate() {
+            if let Some(push_data) = filter_uplink(&push_data, filter.as_deref()) {
+                let client_tx = client_tx.clone();
+                tasks.spawn(async move { (server, client_tx.send(push_data).await) });
+                queued.push(server);
+            }
+        }
+        let mut push_acks = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(push_ack) => push_acks.push(push_ack),
+                Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+                // cancelled by the shutdown of the tokio runtime, reported below
+                Err(_) => (),
+            }
+        }
+        for server in queued {
+            if !push_acks.iter().any(|(answered, _)| *answered == server) {
+                push_acks.push((server, Err(Error::Shutdown)));
+            }
+        }
+        push_acks.sort_by_key(|(server, _)| *server);
+        push_acks
+    }
+
+    /// the sender of a single server, by index
+    pub fn server(&self, server: usize) -> Option<&ClientTx> {
+        self.servers.get(server).map(|(client_tx, _)| client_tx)
+    }
+}
+
+pub struct FanoutRuntime {
+    runtimes: Vec<UdpRuntime>,
+    receivers: Vec<(super::ClientRx, bool)>,
+    sender: mpsc::Sender<(usize, Event)>,
+}
+
+impl FanoutRuntime {
+    pub async fn new(
+        mac: MacAddress,
+        servers: Vec<Upstream>,
+    ) -> Result<(FanoutTx, FanoutRx, FanoutRuntime)> {
+        // before connecting to any server
+        for server in &servers {
+            server.config.validate()?;
+        }
+        let (sender, receiver) = mpsc::channel(100);
+        let mut fanout_tx = FanoutTx {
+            servers: Vec::new(),
+        };
+        let mut runtime = FanoutRuntime {
+            runtimes: Vec::new(),
+            receivers: Vec::new(),
+            sender,
+        };
+        for server in servers {
+            let (client_tx, client_rx, udp_runtime) = UdpRuntime::new_with_config(
+                mac,
+                &server.host,
+                server.down_host.as_deref(),
+                server.config,
+            )
+            .await?;
+            fanout_tx.servers.push((client_tx, server.uplink_filter));
+            runtime.receivers.push((client_rx, server.downlinks));
+            runtime.runtimes.push(udp_runtime);
+        }
+        Ok((fanout_tx, receiver, runtime))
+    }
+
+    pub async fn run(self, shutdown_signal: triggered::Listener) -> Result {
+        let mut tasks = JoinSet::new();
+        for runtime in self.runtimes {
+            tasks.spawn(runtime.run(shutdown_signal.clone()));
+        }
+
+        for (server, (mut client_rx, downlinks)) in self.receivers.into_iter().enumerate() {
+            let sender = self.sender.clone();
+            tasks.spawn(async move {
+                while let Some(event) = client_rx.recv().await {
+                    if let (Event::DownlinkRequest(_request), false) = (&event, downlinks) {
+                        debug!(
+                            server,
+                            token = _request.pull_resp.random_token,
+                            "dropping downlink of an uplink-only server"
+                        );
+                        continue;
+                    }
+                    sender.send((server, event)).await.map_err(
+                        |mpsc::error::SendError((_, event))| mpsc::error::SendError(event),
+                    )?;
+                }
+                Ok(())
+            });
+        }
+
+        // every runtime runs until shutdown, so the first task to return ends the fan-out
+        tokio::select!(
+            _ = shutdown_signal => Ok(()),
+            Some(resp) = tasks.join_next() => resp?,
+        )
+    }
+}
+
+// the uplink restricted to the rxpk the filter accepts; None when nothing is left to send
+fn filter_uplink(
+    push_data: &push_data::Packet,
+    filter: Option<&dyn UplinkFilter>,
+) -> Option<push_data::Packet> {
+    let mut push_data = push_data.clone();
+    if let (Some(filter), Some(rxpk)) = (filter, &mut push_data.data.rxpk) {
+        rxpk.retain(|rxpk| filter.forward(rxpk));
+        if rxpk.is_empty() {
+            push_data.data.rxpk = None;
+        }
+    }
+    (push_data.data.rxpk.is_some() || push_data.data.stat.is_some()).then_some(push_data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_by_crc() {
+        let push_data = push_data::Packet::random();
+        let valid_only = CrcFilter::default();
+        let errors_only = CrcFilter {
+            valid: false,
+            error: true,
+            disabled: false,
+        };
+        assert!(filter_uplink(&push_data, None).is_some());
+        let filtered = filter_uplink(&push_data, Some(&valid_only)).unwrap();
+        assert_eq!(filtered.data.rxpk.unwrap().len(), 1);
+        assert!(filter_uplink(&push_data, Some(&errors_only)).is_none());
+    }
+
+    #[tokio::test]
+    async fn send_to_every_server() {
+        let up = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let host = up.local_addr().unwrap().to_string();
+        let interval = std::time::Duration::from_secs(30);
+        let servers = vec![
+            Upstream::new(host.clone()),
+            Upstream::new(host).config(ClientConfig::default().report_stats(interval, None, None)),
+        ];
+        let (fanout_tx, _fanout_rx, mut runtime) =
+            FanoutRuntime::new(MacAddress::from([1; 8]), servers)
+                .await
+                .unwrap();
+        let stat_intervals: Vec<_> = runtime
+            .runtimes
+            .iter()
+            .map(|runtime| runtime.stat_reporting.as_ref().map(|stats| stats.interval))
+            .collect();
+        assert_eq!(stat_intervals, [None, Some(interval)]);
+
+        // the first server's runtime is gone, which does not keep the uplink from the second
+        drop(runtime.runtimes.remove(0));
+        let push_acks = fanout_tx.send(push_data::Packet::random()).await;
+        assert!(matches!(push_acks[0], (0, Err(_))));
+        assert!(matches!(push_acks[1], (1, Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn a_full_server_does_not_hold_up_the_others() {
+        let up = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let host = up.local_addr().unwrap().to_string();
+        let servers = vec![Upstream::new(host.clone()), Upstream::new(host)];
+        // the runtimes are not run, so nothing empties their queues
+        let (fanout_tx, _fanout_rx, mut runtime) =
+            FanoutRuntime::new(MacAddress::from([1; 8]), servers)
+                .await
+                .unwrap();
+        let full = fanout_tx.server(0).unwrap();
+        while tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            full.send(push_data::Packet::random()),
+        )
+        .await
+        .is_ok()
+        {}
+
+        let send = tokio::spawn(async move { fanout_tx.send(push_data::Packet::random()).await });
+        // the second server has its uplink queued while the first one waits for room
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!send.is_finished());
+        assert!(runtime.runtimes[1].tx.receiver.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_an_error() {
+        let servers = vec![
+            Upstream::new("127.0.0.1:1700"),
+            Upstream::new("127.0.0.1:1701").config(ClientConfig::default().recv_buffer_size(0)),
+        ];
+        let runtime = FanoutRuntime::new(MacAddress::from([1; 8]), servers).await;
+        assert!(matches!(runtime, Err(Error::InvalidConfig(_))));
+    }
+}
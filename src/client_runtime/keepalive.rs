@@ -0,0 +1,156 @@
+/*
+   Tracks whether the server answers PULL_DATA with PULL_ACK. The connection is lost when
+   several PULL_DATA in a row go unanswered, or a frame fails to send, and is back on the
+   next PULL_ACK. With a limit of 0 unanswered PULL_DATA never lose the connection.
+*/
+use super::Event;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// PULL_DATA left without PULL_ACK before the connection is considered lost
+pub const DEFAULT_MISSED_PULL_ACKS: u32 = 3;
+
+/// Round trips of PULL_DATA answered by a PULL_ACK
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PullAckStats {
+    pub sent: u64,
+    pub acked: u64,
+    pub missed: u64,
+    pub last_rtt: Option<Duration>,
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    pub rtt_sum: Duration,
+}
+
+impl PullAckStats {
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        (self.acked > 0).then(|| self.rtt_sum / self.acked as u32)
+    }
+
+    fn observe(&mut self, rtt: Duration) {
+        self.acked += 1;
+        self.last_rtt = Some(rtt);
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        self.max_rtt = Some(self.max_rtt.map_or(rtt, |max| max.max(rtt)));
+        self.rtt_sum += rtt;
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    max_missed: u32,
+    // PULL_DATA awaiting their PULL_ACK, oldest first; a late ACK still proves liveness
+    outstanding: VecDeque<(u16, Instant)>,
+    missed: u32,
+    connected: bool,
+    stats: PullAckStats,
+}
+
+impl Liveness {
+    pub fn new(max_missed: u32) -> Liveness {
+        Liveness {
+            max_missed,
+            outstanding: VecDeque::new(),
+            missed: 0,
+            connected: true,
+            stats: PullAckStats::default(),
+        }
+    }
+
+    // PULL_DATA still unanswered when the next one is sent count as missed
+    pub fn pull_data_sent(&mut self, token: u16, now: Instant) -> Option<Event> {
+        self.stats.sent += 1;
+        if !self.outstanding.is_empty() {
+            self.missed += 1;
+            self.stats.missed += 1;
+        }
+        if self.outstanding.len() >= self.max_missed.max(1) as usize {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back((token, now));
+        if self.max_missed > 0 && self.missed >= self.max_missed {
+            self.lost()
+        } else {
+            None
+        }
+    }
+
+    pub fn pull_ack(&mut self, token: u16, now: Instant) -> Option<Event> {
+        let index = self
+            .outstanding
+            .iter()
+            .position(|(outstanding, _)| *outstanding == token)?;
+        let (_, sent) = self.outstanding[index];
+        // earlier PULL_DATA were already counted as missed
+        self.outstanding.clear();
+        self.missed = 0;
+        self.stats.observe(now.duration_since(sent));
+        (!self.connected).then(|| {
+            self.connected = true;
+            Event::Reconnected
+        })
+    }
+
+    pub fn send_failed(&mut self) -> Option<Event> {
+        self.lost()
+    }
+
+    fn lost(&mut self) -> Option<Event> {
+        self.connected.then(|| {
+            self.connected = false;
+            Event::LostConnection
+        })
+    }
+
+    pub fn stats(&self) -> &PullAckStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missed_pull_acks() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut liveness = Liveness::new(2);
+        assert!(liveness.pull_data_sent(1, now).is_none());
+        assert!(liveness.pull_ack(1, now + ms(40)).is_none());
+        assert!(liveness.pull_data_sent(2, now + ms(100)).is_none());
+        assert!(liveness.pull_data_sent(3, now + ms(200)).is_none());
+        assert!(matches!(
+            liveness.pull_data_sent(4, now + ms(300)),
+            Some(Event::LostConnection)
+        ));
+        assert!(liveness.pull_data_sent(5, now + ms(400)).is_none());
+        // tokens which aged out are ignored
+        assert!(liveness.pull_ack(3, now + ms(410)).is_none());
+        assert!(matches!(
+            liveness.pull_ack(4, now + ms(420)),
+            Some(Event::Reconnected)
+        ));
+
+        let stats = liveness.stats();
+        assert_eq!((stats.sent, stats.acked, stats.missed), (5, 2, 3));
+        assert_eq!(stats.last_rtt, Some(ms(120)));
+        assert_eq!(stats.min_rtt, Some(ms(40)));
+        assert_eq!(stats.mean_rtt(), Some(ms(80)));
+    }
+
+    #[test]
+    fn no_limit_on_missed_pull_acks() {
+        let now = Instant::now();
+        let mut liveness = Liveness::new(0);
+        assert!((1..10).all(|token| liveness.pull_data_sent(token, now).is_none()));
+        assert_eq!(liveness.stats().missed, 8);
+        assert!(liveness.pull_ack(9, now).is_none());
+        assert!(matches!(
+            liveness.send_failed(),
+            Some(Event::LostConnection)
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::metrics::{Counter, Gauge};
+
+// Instruments the client's Rx and Tx loops
+#[derive(Debug, Default)]
+pub(crate) struct ClientMetrics {
+    pub push_data_sent: Counter,
+    pub pull_data_sent: Counter,
+    pub tx_ack_sent: Counter,
+    pub send_errors: Counter,
+    pub pull_resp_received: Counter,
+    pub pull_ack_received: Counter,
+    pub push_ack_received: Counter,
+    pub parse_errors: Counter,
+    pub recv_errors: Counter,
+    pub connected: Gauge,
+}
+
+#[cfg(feature = "metrics")]
+impl ClientMetrics {
+    pub fn render(
+        &self,
+        mac: crate::MacAddress,
+        tx_queue_depth: usize,
+        pull_acks: &super::PullAckStats,
+        push_acks: &super::PushAckStats,
+    ) -> String {
+        let mut encoder = crate::metrics::TextEncoder::default();
+
+        let name = "semtech_udp_client_frames_sent_total";
+        encoder.header(name, "counter", "UDP frames sent, by GWMP identifier");
+        for (frame_type, counter) in [
+            ("push_data", &self.push_data_sent),
+            ("pull_data", &self.pull_data_sent),
+            ("tx_ack", &self.tx_ack_sent),
+        ] {
+            encoder.sample(
+                name,
+                &[("gateway", &mac), ("type", &frame_type)],
+                counter.get(),
+            );
+        }
+
+        let name = "semtech_udp_client_frames_received_total";
+        encoder.header(name, "counter", "UDP frames received, by GWMP identifier");
+        for (frame_type, counter) in [
+            ("pull_resp", &self.pull_resp_received),
+            ("pull_ack", &self.pull_ack_received),
+            ("push_ack", &self.push_ack_received),
+            ("invalid", &self.parse_errors),
+        ] {
+            encoder.sample(
+                name,
+                &[("gateway", &mac), ("type", &frame_type)],
+                counter.get(),
+            );
+        }
+
+        for (name, help, counter) in [
+            (
+                "semtech_udp_client_send_errors_total",
+                "Frames which failed to send",
+                &self.send_errors,
+            ),
+            (
+                "semtech_udp_client_recv_errors_total",
+                "Errors reading from the socket",
+                &self.recv_errors,
+            ),
+        ] {
+            encoder.header(name, "counter", help);
+            encoder.sample(name, &[("gateway", &mac)], counter.get());
+        }
+
+        let name = "semtech_udp_client_connected";
+        encoder.header(
+            name,
+            "gauge",
+            "Whether the server answers PULL_DATA and frames are sent successfully",
+        );
+        encoder.sample(name, &[("gateway", &mac)], self.connected.get());
+
+        let name = "semtech_udp_client_pull_data_missed_total";
+        encoder.header(name, "counter", "Keepalive PULL_DATA which got no PULL_ACK");
+        encoder.sample(name, &[("gateway", &mac)], pull_acks.missed);
+
+        if let Some(rtt) = pull_acks.last_rtt {
+            let name = "semtech_udp_client_pull_ack_rtt_seconds";
+            encoder.header(name, "gauge", "Round trip of the latest acked PULL_DATA");
+            encoder.sample(name, &[("gateway", &mac)], rtt.as_secs_f64());
+        }
+
+        let name = "semtech_udp_client_push_data_total";
+        encoder.header(name, "counter", "PUSH_DATA by outcome");
+        for (outcome, count) in [
+            ("acked", push_acks.acked),
+            ("timed_out", push_acks.timed_out),
+            ("retransmitted", push_acks.retransmissions),
+        ] {
+            encoder.sample(name, &[("gateway", &mac), ("outcome", &outcome)], count);
+        }
+
+        let name = "semtech_udp_client_tx_queue_depth";
+        encoder.header(name, "gauge", "Frames waiting to be sent");
+        encoder.sample(name, &[("gateway", &mac)], tx_queue_depth);
+
+        encoder.finish()
+    }
+}
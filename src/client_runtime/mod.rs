@@ -3,17 +3,44 @@
    run sending and receiving concurrently as tasks,
    receive downlink packets and send uplink packets easily
 */
+use crate::sync::lock;
 use crate::{
     pull_data, pull_resp, push_data, Down, MacAddress, Packet, ParseError, SerializablePacket, Up,
 };
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 use tokio::{
-    net::{ToSocketAddrs, UdpSocket},
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
     sync::mpsc::{self, Receiver, Sender},
+    task::JoinSet,
 };
 
+mod config;
+pub use config::ClientConfig;
+
 mod error;
 pub use error::Error;
+
+mod metrics;
+use metrics::ClientMetrics;
+
+mod keepalive;
+use keepalive::Liveness;
+pub use keepalive::{PullAckStats, DEFAULT_MISSED_PULL_ACKS};
+
+mod uplink;
+use uplink::PushAcks;
+pub use uplink::{PushAck, PushAckPolicy, PushAckStats};
+
+mod stats;
+pub use stats::{Location, LocationProvider, TemperatureProvider};
+use stats::{StatCounters, StatReporting};
+
+mod fanout;
+pub use fanout::{CrcFilter, FanoutRuntime, FanoutRx, FanoutTx, UplinkFilter, Upstream};
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
 pub type RxMessage = Packet;
@@ -21,32 +48,59 @@ pub type TxMessage = Packet;
 
 struct Rx {
     mac: MacAddress,
+    recv_buffer_size: usize,
     udp_sender: mpsc::Sender<TxMessage>,
     client_sender: mpsc::Sender<Event>,
     socket_recv: Arc<UdpSocket>,
+    liveness: Arc<Mutex<Liveness>>,
+    push_acks: Arc<Mutex<PushAcks>>,
+    stat_counters: Arc<Mutex<StatCounters>>,
+    metrics: Arc<ClientMetrics>,
 }
 
+// PUSH_DATA go out through `socket_up`, every other frame through `socket_down`;
+// both are the same socket unless the server uses distinct up and down ports
 struct Tx {
     mac: MacAddress,
     receiver: Receiver<TxMessage>,
     client_sender: mpsc::Sender<Event>,
-    socket_send: Arc<UdpSocket>,
+    socket_up: Arc<UdpSocket>,
+    socket_down: Arc<UdpSocket>,
+    liveness: Arc<Mutex<Liveness>>,
+    push_acks: Arc<Mutex<PushAcks>>,
+    stat_counters: Arc<Mutex<StatCounters>>,
+    metrics: Arc<ClientMetrics>,
 }
 
 pub struct UdpRuntime {
-    rx: Rx,
+    // one receive loop per socket
+    rx: Vec<Rx>,
     tx: Tx,
-    poll_sender: Sender<TxMessage>,
+    // sends the keepalive PULL_DATA and the stats
+    client_tx: ClientTx,
+    keepalive_interval: Duration,
+    stat_reporting: Option<StatReporting>,
+    resolve_interval: Option<Duration>,
+    // hosts resolved again every resolve_interval, with the socket connected to each
+    hosts: Vec<(String, Arc<UdpSocket>)>,
 }
 
 pub type ClientRx = mpsc::Receiver<Event>;
 
 #[derive(Debug)]
 pub enum Event {
+    // a PULL_ACK was received again
     Reconnected,
+    // several PULL_DATA in a row were not acked, or a frame failed to send
     LostConnection,
     DownlinkRequest(DownlinkRequest),
     UnableToParseUdpFrame(ParseError, Vec<u8>),
+    // a server host resolved to another address, which its socket is now connected to
+    ServerAddressChanged {
+        host: String,
+        previous: SocketAddr,
+        current: SocketAddr,
+    },
 }
 
 // A downlink request is sent to the client and contains the necessary
@@ -64,10 +118,12 @@ impl DownlinkRequest {
     }
 
     pub async fn ack(self) -> Result {
+        debug!(token = self.pull_resp.random_token, "acking downlink");
         let ack = self.pull_resp.into_ack_for_gateway(self.mac);
         Ok(self.udp_sender.send(ack.into()).await?)
     }
     pub async fn nack(self, error: super::tx_ack::Error) -> Result {
+        debug!(token = self.pull_resp.random_token, %error, "nacking downlink");
         let nack = self
             .pull_resp
             .into_nack_with_error_for_gateway(error, self.mac);
@@ -78,14 +134,49 @@ impl DownlinkRequest {
 #[derive(Debug, Clone)]
 pub struct ClientTx {
     udp_sender: mpsc::Sender<TxMessage>,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    mac: MacAddress,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    metrics: Arc<ClientMetrics>,
+    liveness: Arc<Mutex<Liveness>>,
+    push_acks: Arc<Mutex<PushAcks>>,
 }
 
 impl ClientTx {
-    pub async fn send(&self, push_data: push_data::Packet) -> Result {
-        Ok(self
+    // returns once the uplink is queued, with a future resolving once the server acknowledged it
+    pub async fn send(&self, mut push_data: push_data::Packet) -> Result<PushAck> {
+        let (token, push_ack) = lock(&self.push_acks).register();
+        push_data.random_token = token;
+        if let Err(e) = self
             .udp_sender
             .send(Packet::Up(Up::PushData(push_data)))
-            .await?)
+            .await
+        {
+            lock(&self.push_acks).unregister(token);
+            return Err(e.into());
+        }
+        Ok(push_ack)
+    }
+
+    pub fn push_ack_stats(&self) -> PushAckStats {
+        lock(&self.push_acks).stats().clone()
+    }
+
+    // round trips of the keepalive PULL_DATA
+    pub fn pull_ack_stats(&self) -> PullAckStats {
+        lock(&self.liveness).stats().clone()
+    }
+
+    // the runtime's metrics in the Prometheus text format
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String {
+        let tx_queue_depth = self.udp_sender.max_capacity() - self.udp_sender.capacity();
+        self.metrics.render(
+            self.mac,
+            tx_queue_depth,
+            &self.pull_ack_stats(),
+            &self.push_ack_stats(),
+        )
     }
 }
 
@@ -103,68 +194,201 @@ impl UdpRuntime {
         mac: MacAddress,
         host: H,
     ) -> Result<(ClientTx, ClientRx, UdpRuntime)> {
-        let socket = UdpSocket::bind(&outbound_socket)
-            .await
-            .map_err(|io_error| Error::Binding { io_error })?;
-        // "connecting" filters for only frames from the server
-        socket
-            .connect(host)
-            .await
-            .map_err(|io_error| Error::Binding { io_error })?;
+        let socket = Arc::new(connect(outbound_socket, host).await?);
+        Ok(Self::with_sockets(
+            mac,
+            socket.clone(),
+            socket,
+            &ClientConfig::default(),
+        ))
+    }
 
-        let socket_recv = Arc::new(socket);
-        let socket_send = socket_recv.clone();
+    // `host`, and `down_host` when the server uses distinct up and down ports, are
+    // "name:port" strings which are resolved again every `resolve_interval` of the config
+    pub async fn new_with_config(
+        mac: MacAddress,
+        host: &str,
+        down_host: Option<&str>,
+        config: ClientConfig,
+    ) -> Result<(ClientTx, ClientRx, UdpRuntime)> {
+        config.validate()?;
+        let outbound_socket = SocketAddr::from(([0, 0, 0, 0], 0));
+        let socket_up = Arc::new(connect(outbound_socket, host).await?);
+        let mut hosts = vec![(host.to_string(), socket_up.clone())];
+        let socket_down = match down_host {
+            Some(down_host) => {
+                let socket_down = Arc::new(connect(outbound_socket, down_host).await?);
+                hosts.push((down_host.to_string(), socket_down.clone()));
+                socket_down
+            }
+            None => socket_up.clone(),
+        };
+        let (client_tx, client_rx, mut runtime) =
+            Self::with_sockets(mac, socket_up, socket_down, &config);
+        runtime.hosts = hosts;
+        Ok((client_tx, client_rx, runtime))
+    }
+
+    // PUSH_DATA are sent to `up_host`, and PULL_DATA, PULL_RESP and TX_ACK are exchanged
+    // with `down_host`, like the serv_port_up and serv_port_down of the reference forwarder
+    pub async fn new_with_up_down<U: ToSocketAddrs, D: ToSocketAddrs>(
+        mac: MacAddress,
+        up_host: U,
+        down_host: D,
+    ) -> Result<(ClientTx, ClientRx, UdpRuntime)> {
+        let outbound_socket = SocketAddr::from(([0, 0, 0, 0], 0));
+        let socket_up = connect(outbound_socket, up_host).await?;
+        let socket_down = connect(outbound_socket, down_host).await?;
+        Ok(Self::with_sockets(
+            mac,
+            Arc::new(socket_up),
+            Arc::new(socket_down),
+            &ClientConfig::default(),
+        ))
+    }
 
+    fn with_sockets(
+        mac: MacAddress,
+        socket_up: Arc<UdpSocket>,
+        socket_down: Arc<UdpSocket>,
+        config: &ClientConfig,
+    ) -> (ClientTx, ClientRx, UdpRuntime) {
         let (tx_sender, tx_receiver) = mpsc::channel(100);
         let (downlink_request_tx, downlink_request_rx) = mpsc::channel(100);
 
+        let metrics = Arc::new(ClientMetrics::default());
+        metrics.connected.set(1);
+        let liveness = Arc::new(Mutex::new(Liveness::new(config.missed_pull_acks)));
+        let push_acks = Arc::new(Mutex::new(PushAcks::new(config.push_ack_policy)));
+        let stat_counters = Arc::new(Mutex::new(StatCounters::default()));
+
         let client_sender = ClientTx {
             udp_sender: tx_sender.clone(),
+            mac,
+            metrics: metrics.clone(),
+            liveness: liveness.clone(),
+            push_acks: push_acks.clone(),
         };
 
-        Ok((
-            client_sender,
+        let mut sockets = vec![socket_down.clone()];
+        if !Arc::ptr_eq(&socket_up, &socket_down) {
+            sockets.push(socket_up.clone());
+        }
+        let rx = sockets
+            .into_iter()
+            .map(|socket_recv| Rx {
+                mac,
+                recv_buffer_size: config.recv_buffer_size,
+                client_sender: downlink_request_tx.clone(),
+                udp_sender: tx_sender.clone(),
+                socket_recv,
+                liveness: liveness.clone(),
+                push_acks: push_acks.clone(),
+                stat_counters: stat_counters.clone(),
+                metrics: metrics.clone(),
+            })
+            .collect();
+
+        (
+            client_sender.clone(),
             downlink_request_rx,
             UdpRuntime {
-                rx: Rx {
-                    mac,
-                    client_sender: downlink_request_tx.clone(),
-                    udp_sender: tx_sender.clone(),
-                    socket_recv,
-                },
-                poll_sender: tx_sender,
+                rx,
+                client_tx: client_sender,
+                keepalive_interval: config.keepalive_interval,
+                stat_reporting: config.stat_interval.map(|interval| StatReporting {
+                    interval,
+                    location: config.location.clone(),
+                    temperature: config.temperature.clone(),
+                }),
+                resolve_interval: config.resolve_interval,
+                hosts: Vec::new(),
                 tx: Tx {
                     mac,
                     client_sender: downlink_request_tx,
                     receiver: tx_receiver,
-                    socket_send,
+                    socket_up,
+                    socket_down,
+                    liveness,
+                    push_acks,
+                    stat_counters,
+                    metrics,
                 },
             },
-        ))
+        )
     }
 
     pub async fn run(self, shutdown_signal: triggered::Listener) -> Result {
-        let (rx, tx, poll_sender) = (self.rx, self.tx, self.poll_sender);
-        // udp_runtime_rx reads from the UDP port
-        let udp_listener = tokio::spawn(rx.run());
+        let (rx, tx, client_tx) = (self.rx, self.tx, self.client_tx);
+        let mac = tx.mac;
+        let stat_counters = tx.stat_counters.clone();
+        let client_sender = tx.client_sender.clone();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("gateway", mac = %tx.mac);
+        let mut tasks = JoinSet::new();
+
+        // udp_runtime_rx reads from the UDP ports
+        for rx in rx {
+            let udp_listener = rx.run();
+            #[cfg(feature = "tracing")]
+            let udp_listener = tracing::Instrument::instrument(udp_listener, span.clone());
+            tasks.spawn(udp_listener);
+        }
 
-        // udp_runtime_tx writes to the UDP port
+        // udp_runtime_tx writes to the UDP ports
         // by receiving packets from the sender channel
-        let udp_writer = tokio::spawn(tx.run());
+        let udp_writer = tx.run();
+        #[cfg(feature = "tracing")]
+        let udp_writer = tracing::Instrument::instrument(udp_writer, span);
+        tasks.spawn(udp_writer);
+
+        if let Some(stat_reporting) = self.stat_reporting {
+            let client_tx = client_tx.clone();
+            tasks.spawn(async move {
+                loop {
+                    sleep(stat_reporting.interval).await;
+                    let stat =
+                        stat_reporting.stat(&stat_counters, &client_tx.push_ack_stats(), mac);
+                    // the stat is acknowledged like any PUSH_DATA, and counted in the next ackr
+                    client_tx.send(stat).await?;
+                }
+            });
+        }
+
+        if let (Some(interval), false) = (self.resolve_interval, self.hosts.is_empty()) {
+            let hosts = self.hosts;
+            tasks.spawn(async move {
+                loop {
+                    sleep(interval).await;
+                    for (host, socket) in &hosts {
+                        if let Some((previous, current)) = reconnect(host, socket).await {
+                            info!(host, %previous, %current, "server address changed");
+                            client_sender
+                                .send(Event::ServerAddressChanged {
+                                    host: host.clone(),
+                                    previous,
+                                    current,
+                                })
+                                .await?;
+                        }
+                    }
+                }
+            });
+        }
 
-        let pull_req_sender = tokio::spawn(async move {
+        let keepalive_interval = self.keepalive_interval;
+        tasks.spawn(async move {
             loop {
                 let packet = pull_data::Packet::new(rand::random());
-                poll_sender.send(packet.into()).await?;
-                sleep(Duration::from_millis(10000)).await;
+                client_tx.udp_sender.send(packet.into()).await?;
+                sleep(keepalive_interval).await;
             }
         });
 
+        // every task loops forever, so the first one to return ends the runtime
         tokio::select!(
             _ = shutdown_signal => Ok(()),
-            resp = udp_listener => resp?,
-            resp = udp_writer => resp?,
-            resp = pull_req_sender => resp?,
+            Some(resp) = tasks.join_next() => resp?,
         )
     }
 }
@@ -172,6 +396,66 @@ impl UdpRuntime {
 use std::time::Duration;
 use tokio::time::sleep;
 
+// waits forever without a deadline
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn connection_changed(
+    metrics: &ClientMetrics,
+    client_sender: &mpsc::Sender<Event>,
+    event: Event,
+) -> Result {
+    if matches!(event, Event::Reconnected) {
+        metrics.connected.set(1);
+        info!("reconnected");
+    } else {
+        metrics.connected.set(0);
+        info!("lost connection");
+    }
+    Ok(client_sender.send(event).await?)
+}
+
+// "connecting" filters for only frames from the server
+async fn connect<L: ToSocketAddrs, H: ToSocketAddrs>(local: L, host: H) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind(local)
+        .await
+        .map_err(|io_error| Error::Binding { io_error })?;
+    socket
+        .connect(host)
+        .await
+        .map_err(|io_error| Error::Connection { io_error })?;
+    Ok(socket)
+}
+
+// connects the socket to the host's new address, unless it still resolves to the current
+// one, and returns both; resolution errors keep the current address
+async fn reconnect(host: &str, socket: &UdpSocket) -> Option<(SocketAddr, SocketAddr)> {
+    let local = socket.local_addr().ok()?;
+    let previous = socket.peer_addr().ok()?;
+    let addrs: Vec<SocketAddr> = match lookup_host(host).await {
+        Ok(addrs) => addrs
+            .filter(|addr| addr.is_ipv4() == local.is_ipv4())
+            .collect(),
+        Err(_error) => {
+            warn!(host, error = %_error, "failed to resolve server");
+            return None;
+        }
+    };
+    if addrs.contains(&previous) {
+        return None;
+    }
+    let addr = *addrs.first()?;
+    if let Err(_error) = socket.connect(addr).await {
+        warn!(host, %addr, error = %_error, "failed to reconnect to server");
+        return None;
+    }
+    Some((previous, addr))
+}
+
 impl Rx {
     fn new_downlink_request(&self, pull_resp: pull_resp::Packet) -> DownlinkRequest {
         DownlinkRequest {
@@ -182,7 +466,7 @@ impl Rx {
     }
 
     pub async fn run(self) -> Result {
-        let mut buf = vec![0u8; 1024];
+        let mut buf = vec![0u8; self.recv_buffer_size];
         loop {
             match self.socket_recv.recv(&mut buf).await {
                 Ok(n) => {
@@ -191,19 +475,33 @@ impl Rx {
                             // pull_resp is a request to sent an RF packet
                             // we hand this off to the runtime client
                             Down::PullResp(pull_resp) => {
+                                self.metrics.pull_resp_received.inc();
+                                lock(&self.stat_counters).pull_resp_received();
+                                debug!(token = pull_resp.random_token, "downlink requested");
                                 let downlink_request = self.new_downlink_request(*pull_resp);
                                 self.client_sender
                                     .send(Event::DownlinkRequest(downlink_request))
                                     .await?;
                             }
-                            // pull_ack just lets us know that the "connection is open"
-                            // could potentially have a timer that waits for these on every
-                            // pull_data frame
-                            Down::PullAck(_) => (),
+                            // pull_ack lets us know that the "connection is open"
+                            Down::PullAck(pull_ack) => {
+                                self.metrics.pull_ack_received.inc();
+                                let event = lock(&self.liveness)
+                                    .pull_ack(pull_ack.random_token, Instant::now());
+                                if let Some(event) = event {
+                                    connection_changed(&self.metrics, &self.client_sender, event)
+                                        .await?;
+                                }
+                            }
                             // push_ack is sent immediately after push_data (uplink, ie: RF packet received)
-                            Down::PushAck(_) => (),
+                            Down::PushAck(push_ack) => {
+                                self.metrics.push_ack_received.inc();
+                                lock(&self.push_acks).acked(push_ack.random_token, Instant::now());
+                            }
                         },
                         Err(e) => {
+                            self.metrics.parse_errors.inc();
+                            warn!(error = %e, "unable to parse UDP frame");
                             let mut vec = Vec::new();
                             vec.extend_from_slice(&buf[0..n]);
 
@@ -213,7 +511,9 @@ impl Rx {
                         }
                     }
                 }
-                Err(_) => {
+                Err(_error) => {
+                    self.metrics.recv_errors.inc();
+                    warn!(error = %_error, "error reading from socket");
                     // back off of CPU
                     sleep(Duration::from_millis(100)).await;
                 }
@@ -223,45 +523,188 @@ impl Rx {
 }
 
 impl Tx {
+    async fn retransmit(&self) {
+        let frames = lock(&self.push_acks).retransmissions_due(Instant::now());
+        for frame in frames {
+            if let Err(_error) = self.socket_up.send(&frame).await {
+                self.metrics.send_errors.inc();
+                warn!(error = %_error, "failed to retransmit PUSH_DATA");
+            }
+        }
+    }
+
     pub async fn run(mut self) -> Result {
         let mut buf = vec![0u8; 1024];
-        let mut connected = true;
         loop {
-            let tx = self.receiver.recv().await;
+            let deadline = lock(&self.push_acks).next_deadline();
+            let tx = tokio::select! {
+                tx = self.receiver.recv() => tx,
+                _ = sleep_until(deadline) => {
+                    self.retransmit().await;
+                    continue;
+                }
+            };
             if let Some(mut data) = tx {
                 match &mut data {
                     Packet::Up(ref mut up) => {
                         up.set_gateway_mac(self.mac);
                         match up {
-                            Up::PushData(ref mut push_data) => {
-                                push_data.random_token = rand::random()
-                            }
+                            // the token was allocated by ClientTx::send
+                            Up::PushData(_) => self.metrics.push_data_sent.inc(),
                             Up::PullData(ref mut pull_data) => {
+                                self.metrics.pull_data_sent.inc();
                                 pull_data.random_token = rand::random()
                             }
-                            Up::TxAck(_) => (),
+                            Up::TxAck(_) => self.metrics.tx_ack_sent.inc(),
                         }
                     }
                     Packet::Down(_) => panic!("Should not be sending any down packets"),
                 }
 
                 let n = data.serialize(&mut buf)? as usize;
+                let socket = match &data {
+                    Packet::Up(Up::PushData(push_data)) => {
+                        lock(&self.push_acks).sent(
+                            push_data.random_token,
+                            &buf[..n],
+                            Instant::now(),
+                        );
+                        &self.socket_up
+                    }
+                    _ => &self.socket_down,
+                };
 
-                match self.socket_send.send(&buf[..n]).await {
+                let event = match socket.send(&buf[..n]).await {
                     Ok(_) => {
-                        if !connected {
-                            connected = true;
-                            self.client_sender.send(Event::Reconnected).await?;
+                        debug!(?data, "frame sent");
+                        match &data {
+                            Packet::Up(Up::PushData(push_data)) => {
+                                lock(&self.stat_counters).push_data_sent(push_data);
+                                None
+                            }
+                            Packet::Up(Up::TxAck(tx_ack)) => {
+                                lock(&self.stat_counters).tx_ack_sent(tx_ack);
+                                None
+                            }
+                            Packet::Up(Up::PullData(pull_data)) => lock(&self.liveness)
+                                .pull_data_sent(pull_data.random_token, Instant::now()),
+                            _ => None,
                         }
                     }
-                    Err(_) => {
-                        if connected {
-                            connected = false;
-                            self.client_sender.send(Event::LostConnection).await?;
-                        }
+                    Err(_error) => {
+                        self.metrics.send_errors.inc();
+                        warn!(?data, error = %_error, "failed to send frame");
+                        lock(&self.liveness).send_failed()
                     }
+                };
+                if let Some(event) = event {
+                    connection_changed(&self.metrics, &self.client_sender, event).await?;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{push_ack, DataRate, Modulation};
+    use pull_resp::{PhyData, Time, TxPk};
+    use tokio::time::timeout;
+
+    async fn send(socket: &UdpSocket, packet: impl SerializablePacket, to: SocketAddr) {
+        let mut buf = [0u8; 1024];
+        let n = packet.serialize(&mut buf).unwrap() as usize;
+        socket.send_to(&buf[..n], to).await.unwrap();
+    }
+
+    // the frame with the client address it was sent from
+    async fn recv_from(socket: &UdpSocket) -> (Up, SocketAddr) {
+        let mut buf = [0u8; 1024];
+        let (n, src) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        (Packet::parse_uplink(&buf[..n]).unwrap(), src)
+    }
+
+    fn txpk() -> TxPk {
+        TxPk {
+            time: Time::immediate(),
+            freq: 902.8,
+            rfch: 0,
+            powe: 27,
+            modu: Modulation::LORA,
+            datr: DataRate::default(),
+            codr: Some(crate::CodingRate::_4_5),
+            ipol: true,
+            data: PhyData::new(vec![1, 2, 3, 4]),
+            fdev: None,
+            prea: None,
+            ncrc: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn up_and_down_sockets() {
+        let up = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let down = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mac = MacAddress::from([1; 8]);
+        let (client_tx, mut client_rx, runtime) =
+            UdpRuntime::new_with_up_down(mac, up.local_addr().unwrap(), down.local_addr().unwrap())
+                .await
+                .unwrap();
+        let (_shutdown_trigger, shutdown_signal) = triggered::trigger();
+        tokio::spawn(runtime.run(shutdown_signal));
+
+        // the keepalive PULL_DATA, answered with a PULL_ACK and a PULL_RESP
+        let (pull_data, client_down) = recv_from(&down).await;
+        let Up::PullData(pull_data) = pull_data else {
+            panic!("expected PULL_DATA, got {pull_data:?}");
+        };
+        assert_eq!(pull_data.gateway_mac, mac);
+        send(&down, pull_data.into_ack(), client_down).await;
+        let pull_resp = pull_resp::Packet {
+            random_token: 7,
+            data: pull_resp::Data::from_txpk(txpk()),
+        };
+        send(&down, pull_resp, client_down).await;
+        let Some(Event::DownlinkRequest(request)) = client_rx.recv().await else {
+            panic!("expected a downlink request");
+        };
+        request.ack().await.unwrap();
+        let (tx_ack, src) = recv_from(&down).await;
+        assert!(matches!(tx_ack, Up::TxAck(tx_ack) if tx_ack.random_token == 7));
+        assert_eq!(src, client_down);
+
+        // PUSH_DATA go out through the other socket
+        let push_ack = client_tx.send(push_data::Packet::random()).await.unwrap();
+        let (push_data, client_up) = recv_from(&up).await;
+        let Up::PushData(push_data) = push_data else {
+            panic!("expected PUSH_DATA, got {push_data:?}");
+        };
+        assert_ne!(client_up, client_down);
+        let ack = push_ack::Packet {
+            random_token: push_data.random_token,
+        };
+        send(&up, ack, client_up).await;
+        assert!(push_ack.await.is_ok());
+
+        let mut buf = [0u8; 1024];
+        assert!(up.try_recv(&mut buf).is_err());
+        assert!(down.try_recv(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect_to_a_new_address() {
+        let socket = connect("127.0.0.1:0", "127.0.0.2:1700").await.unwrap();
+        let previous = "127.0.0.2:1700".parse().unwrap();
+        let current = "127.0.0.1:1700".parse().unwrap();
+        assert_eq!(
+            reconnect("127.0.0.1:1700", &socket).await,
+            Some((previous, current))
+        );
+        assert_eq!(socket.peer_addr().unwrap(), current);
+        assert_eq!(reconnect("127.0.0.1:1700", &socket).await, None);
+    }
+}
@@ -0,0 +1,193 @@
+/*
+   Counts what a real forwarder reports in its periodic `stat` object: uplinks handed to
+   the runtime, downlinks requested by the server and those the caller acked. Each report
+   covers the interval since the previous one.
+*/
+use super::PushAckStats;
+use crate::push_data::{Packet, Stat, CRC};
+use crate::sync::lock;
+use crate::{tx_ack, MacAddress};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Position of the gateway, as reported in the `lati`, `long` and `alti` fields of a stat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub lati: f64,
+    pub long: f64,
+    pub alti: i64,
+}
+
+pub trait LocationProvider: fmt::Debug + Send + Sync {
+    fn location(&self) -> Option<Location>;
+}
+
+// a gateway which does not move
+impl LocationProvider for Location {
+    fn location(&self) -> Option<Location> {
+        Some(*self)
+    }
+}
+
+pub trait TemperatureProvider: fmt::Debug + Send + Sync {
+    /// Current temperature in degrees Celsius
+    fn temperature(&self) -> Option<f64>;
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StatCounters {
+    rxnb: u64,
+    rxok: u64,
+    rxfw: u64,
+    dwnb: u64,
+    txnb: u64,
+    // PUSH_DATA sent and acked as of the previous report
+    push_acks: (u64, u64),
+}
+
+impl StatCounters {
+    // stat frames are not radio packets, so only their rxpk count
+    pub fn push_data_sent(&mut self, push_data: &Packet) {
+        let rxpk = push_data.data.rxpk.as_deref().unwrap_or_default();
+        self.rxnb += rxpk.len() as u64;
+        self.rxok += rxpk
+            .iter()
+            .filter(|rxpk| rxpk.crc_status() == CRC::OK)
+            .count() as u64;
+        // everything handed to the runtime is forwarded
+        self.rxfw += rxpk.len() as u64;
+    }
+
+    pub fn pull_resp_received(&mut self) {
+        self.dwnb += 1;
+    }
+
+    // an adjusted transmit power is only a warning, the packet was still emitted
+    pub fn tx_ack_sent(&mut self, tx_ack: &tx_ack::Packet) {
+        if matches!(
+            tx_ack.get_result(),
+            Ok(_) | Err(tx_ack::Error::AdjustedTransmitPower(..))
+        ) {
+            self.txnb += 1;
+        }
+    }
+
+    // resets the counters; `ackr` covers the PUSH_DATA sent since the previous report
+    pub fn report(&mut self, push_acks: &PushAckStats, time: SystemTime) -> Stat {
+        let (sent, acked) = (
+            push_acks.sent - self.push_acks.0,
+            push_acks.acked - self.push_acks.1,
+        );
+        self.push_acks = (push_acks.sent, push_acks.acked);
+        let stat = Stat {
+            time: utc_time(time),
+            lati: None,
+            long: None,
+            alti: None,
+            rxnb: self.rxnb,
+            rxok: self.rxok,
+            rxfw: self.rxfw,
+            // acks of PUSH_DATA sent before the interval may make it exceed 100
+            ackr: (sent > 0).then(|| (acked as f64 * 100.0 / sent as f64).min(100.0)),
+            dwnb: self.dwnb,
+            txnb: self.txnb,
+            temp: None,
+        };
+        *self = StatCounters {
+            push_acks: self.push_acks,
+            ..Default::default()
+        };
+        stat
+    }
+}
+
+// what UdpRuntime::run needs to send a stat every interval
+#[derive(Debug)]
+pub(crate) struct StatReporting {
+    pub interval: Duration,
+    pub location: Option<Arc<dyn LocationProvider>>,
+    pub temperature: Option<Arc<dyn TemperatureProvider>>,
+}
+
+impl StatReporting {
+    pub fn stat(
+        &self,
+        counters: &Mutex<StatCounters>,
+        push_acks: &PushAckStats,
+        mac: MacAddress,
+    ) -> Packet {
+        let mut stat = lock(counters).report(push_acks, SystemTime::now());
+        if let Some(location) = self.location.as_ref().and_then(|p| p.location()) {
+            (stat.lati, stat.long, stat.alti) = (
+                Some(location.lati),
+                Some(location.long),
+                Some(location.alti),
+            );
+        }
+        stat.temp = self.temperature.as_ref().and_then(|p| p.temperature());
+        Packet::from_stat(mac, stat)
+    }
+}
+
+// "2014-01-12 08:59:28 GMT", as sent by the reference forwarder
+fn utc_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // days to civil date, from Howard Hinnant's chrono-compatible algorithms
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} GMT",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stat_interval() {
+        let mut counters = StatCounters::default();
+        counters.push_data_sent(&Packet::random());
+        counters.pull_resp_received();
+        counters.pull_resp_received();
+        let nack = tx_ack::Packet {
+            random_token: 0,
+            gateway_mac: MacAddress::from([0; 8]),
+            data: tx_ack::Data::new_with_error(tx_ack::Error::TooLate),
+        };
+        counters.tx_ack_sent(&nack);
+
+        let time = UNIX_EPOCH + Duration::from_secs(1389517168);
+        let push_acks = PushAckStats {
+            sent: 4,
+            acked: 3,
+            ..Default::default()
+        };
+        let stat = counters.report(&push_acks, time);
+        assert_eq!(stat.time, "2014-01-12 08:59:28 GMT");
+        assert_eq!((stat.rxnb, stat.rxok, stat.rxfw), (1, 1, 1));
+        assert_eq!((stat.dwnb, stat.txnb), (2, 0));
+        assert_eq!(stat.ackr, Some(75.0));
+
+        let stat = counters.report(&push_acks, time);
+        assert_eq!((stat.rxnb, stat.dwnb), (0, 0));
+        assert_eq!(stat.ackr, None);
+    }
+}
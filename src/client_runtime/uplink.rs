@@ -0,0 +1,213 @@
+/*
+   Tracks PUSH_DATA until the server acknowledges them with a PUSH_ACK. Unacknowledged
+   frames are retransmitted with the same token, as allowed by the policy, and the sender
+   learns whether the uplink was acknowledged through a PushAck future.
+*/
+use super::{Error, Result};
+use crate::duration::millis;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+const DEFAULT_PUSH_ACK_TIMEOUT: u64 = 1;
+
+/// How long a PUSH_DATA waits for its PUSH_ACK, and how often it is sent again meanwhile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushAckPolicy {
+    #[serde(rename = "timeout_ms", with = "millis")]
+    pub timeout: Duration,
+    pub retransmissions: u32,
+}
+
+impl Default for PushAckPolicy {
+    fn default() -> Self {
+        PushAckPolicy {
+            timeout: Duration::from_secs(DEFAULT_PUSH_ACK_TIMEOUT),
+            retransmissions: 0,
+        }
+    }
+}
+
+/// Counters of PUSH_DATA; retransmissions are not counted as sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PushAckStats {
+    pub sent: u64,
+    pub acked: u64,
+    pub retransmissions: u64,
+    pub timed_out: u64,
+}
+
+impl PushAckStats {
+    /// Percentage of the PUSH_DATA sent which were acknowledged, as in the `ackr` of a stat
+    pub fn ackr(&self) -> Option<f64> {
+        (self.sent > 0).then(|| self.acked as f64 * 100.0 / self.sent as f64)
+    }
+}
+
+/// Resolves with the round trip once the PUSH_DATA is acknowledged, or fails with
+/// `Error::PushAckTimeout` once it and its retransmissions went unanswered
+#[derive(Debug)]
+pub struct PushAck(oneshot::Receiver<Result<Duration>>);
+
+impl Future for PushAck {
+    type Output = Result<Duration>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.0).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            // the runtime stopped before the uplink was acknowledged or timed out
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::Shutdown)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingUplink {
+    responder: oneshot::Sender<Result<Duration>>,
+    // serialized frame, first transmission and next deadline, once sent
+    sent: Option<(Vec<u8>, Instant, Instant)>,
+    retransmissions: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct PushAcks {
+    policy: PushAckPolicy,
+    pending: HashMap<u16, PendingUplink>,
+    stats: PushAckStats,
+}
+
+impl PushAcks {
+    pub fn new(policy: PushAckPolicy) -> PushAcks {
+        PushAcks {
+            policy,
+            pending: HashMap::new(),
+            stats: PushAckStats::default(),
+        }
+    }
+
+    // allocates a token free among the pending uplinks
+    pub fn register(&mut self) -> (u16, PushAck) {
+        let mut token = rand::random();
+        while self.pending.contains_key(&token) {
+            token = rand::random();
+        }
+        let (responder, receiver) = oneshot::channel();
+        self.pending.insert(
+            token,
+            PendingUplink {
+                responder,
+                sent: None,
+                retransmissions: 0,
+            },
+        );
+        (token, PushAck(receiver))
+    }
+
+    // the uplink could not be queued
+    pub fn unregister(&mut self, token: u16) {
+        self.pending.remove(&token);
+    }
+
+    pub fn sent(&mut self, token: u16, frame: &[u8], now: Instant) {
+        if let Some(uplink) = self.pending.get_mut(&token) {
+            if uplink.sent.is_none() {
+                self.stats.sent += 1;
+                uplink.sent = Some((frame.to_vec(), now, now + self.policy.timeout));
+            }
+        }
+    }
+
+    pub fn acked(&mut self, token: u16, now: Instant) {
+        if let Some(PendingUplink {
+            responder,
+            sent: Some((_, first_sent, _)),
+            ..
+        }) = self.pending.remove(&token)
+        {
+            self.stats.acked += 1;
+            let _ = responder.send(Ok(now.duration_since(first_sent)));
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .filter_map(|uplink| uplink.sent.as_ref().map(|(_, _, deadline)| *deadline))
+            .min()
+    }
+
+    // frames to send again; uplinks out of retransmissions fail
+    pub fn retransmissions_due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let policy = self.policy;
+        let mut frames = Vec::new();
+        let mut timed_out = Vec::new();
+        for (token, uplink) in self.pending.iter_mut() {
+            let Some((frame, _, deadline)) = &mut uplink.sent else {
+                continue;
+            };
+            if *deadline > now {
+                continue;
+            }
+            if uplink.retransmissions < policy.retransmissions {
+                uplink.retransmissions += 1;
+                *deadline = now + policy.timeout;
+                frames.push(frame.clone());
+            } else {
+                timed_out.push(*token);
+            }
+        }
+        self.stats.retransmissions += frames.len() as u64;
+        for token in timed_out {
+            if let Some(uplink) = self.pending.remove(&token) {
+                self.stats.timed_out += 1;
+                let _ = uplink.responder.send(Err(Error::PushAckTimeout));
+            }
+        }
+        frames
+    }
+
+    pub fn stats(&self) -> &PushAckStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn retransmit_until_acked() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut push_acks = PushAcks::new(PushAckPolicy {
+            timeout: second,
+            retransmissions: 1,
+        });
+        let (acked, acked_ack) = push_acks.register();
+        let (lost, lost_ack) = push_acks.register();
+        push_acks.sent(acked, &[1], now);
+        push_acks.sent(lost, &[2], now);
+        assert_eq!(push_acks.next_deadline(), Some(now + second));
+
+        let mut frames = push_acks.retransmissions_due(now + second);
+        frames.sort();
+        assert_eq!(frames, vec![vec![1], vec![2]]);
+        push_acks.acked(acked, now + second * 3 / 2);
+        assert!(push_acks.retransmissions_due(now + second * 2).is_empty());
+
+        assert_eq!(acked_ack.await.unwrap(), second * 3 / 2);
+        assert!(matches!(lost_ack.await, Err(Error::PushAckTimeout)));
+        let stats = push_acks.stats();
+        assert_eq!((stats.sent, stats.acked, stats.timed_out), (2, 1, 1));
+        assert_eq!(stats.retransmissions, 2);
+        assert_eq!(stats.ackr(), Some(50.0));
+    }
+}
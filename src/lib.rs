@@ -1,11 +1,30 @@
 mod packet;
 pub use packet::*;
 
+#[cfg(any(feature = "server", feature = "client"))]
+mod metrics;
+
+#[cfg(any(feature = "server", feature = "client"))]
+mod duration;
+
+#[cfg(any(feature = "server", feature = "client"))]
+mod sync;
+
+#[cfg(any(feature = "server", feature = "client"))]
+#[macro_use]
+mod trace;
+
 #[cfg(feature = "server")]
 pub mod server_runtime;
 
 #[cfg(feature = "client")]
 pub mod client_runtime;
 
+#[cfg(feature = "class_b")]
+pub mod class_b;
+
+#[cfg(feature = "lorawan")]
+pub mod lorawan;
+
 #[cfg(test)]
 mod tests;
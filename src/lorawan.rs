@@ -0,0 +1,385 @@
+/*
+   Read-only view of the LoRaWAN PHYPayload carried in RxPk and TxPk frames.
+
+   Only the unencrypted header fields are exposed: MHDR, DevAddr, FCtrl, FCnt, FPort,
+   the EUIs of join requests and the MIC bytes. No cryptography is performed, so FRMPayload
+   and join-accepts are returned as opaque (encrypted) bytes.
+
+   PHYPayload = MHDR | MACPayload | MIC
+   MACPayload = FHDR | FPort | FRMPayload
+   FHDR       = DevAddr | FCtrl | FCnt | FOpts
+*/
+use std::fmt;
+use thiserror::Error;
+
+const MHDR_LEN: usize = 1;
+const MIC_LEN: usize = 4;
+const FHDR_MIN_LEN: usize = 7;
+const JOIN_REQUEST_LEN: usize = MHDR_LEN + 8 + 8 + 2 + MIC_LEN;
+const JOIN_ACCEPT_LEN: usize = MHDR_LEN + 12 + MIC_LEN;
+const JOIN_ACCEPT_CFLIST_LEN: usize = JOIN_ACCEPT_LEN + 16;
+const DATA_MIN_LEN: usize = MHDR_LEN + FHDR_MIN_LEN + MIC_LEN;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("PHYPayload is empty")]
+    Empty,
+    #[error("invalid {mtype:?} length: {len}")]
+    InvalidLength { mtype: MType, len: usize },
+    #[error("FOptsLen of {fopts_len} exceeds the {len} byte frame")]
+    InvalidFOptsLen { fopts_len: usize, len: usize },
+    #[error("unsupported LoRaWAN major version: {0}")]
+    UnsupportedMajor(u8),
+}
+
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MType {
+    JoinRequest,
+    JoinAccept,
+    UnconfirmedDataUp,
+    UnconfirmedDataDown,
+    ConfirmedDataUp,
+    ConfirmedDataDown,
+    RejoinRequest,
+    Proprietary,
+}
+
+impl MType {
+    fn from_mhdr(mhdr: u8) -> MType {
+        match mhdr >> 5 {
+            0 => MType::JoinRequest,
+            1 => MType::JoinAccept,
+            2 => MType::UnconfirmedDataUp,
+            3 => MType::UnconfirmedDataDown,
+            4 => MType::ConfirmedDataUp,
+            5 => MType::ConfirmedDataDown,
+            6 => MType::RejoinRequest,
+            _ => MType::Proprietary,
+        }
+    }
+
+    pub fn is_uplink(&self) -> bool {
+        matches!(
+            self,
+            MType::JoinRequest
+                | MType::UnconfirmedDataUp
+                | MType::ConfirmedDataUp
+                | MType::RejoinRequest
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DevAddr(pub u32);
+
+impl DevAddr {
+    fn from_le(bytes: &[u8]) -> DevAddr {
+        DevAddr(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl fmt::Display for DevAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X}", self.0)
+    }
+}
+
+impl From<DevAddr> for u32 {
+    fn from(dev_addr: DevAddr) -> u32 {
+        dev_addr.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Eui64(pub u64);
+
+impl Eui64 {
+    fn from_le(bytes: &[u8]) -> Eui64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[..8]);
+        Eui64(u64::from_le_bytes(buf))
+    }
+}
+
+impl fmt::Display for Eui64 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016X}", self.0)
+    }
+}
+
+impl From<Eui64> for u64 {
+    fn from(eui: Eui64) -> u64 {
+        eui.0
+    }
+}
+
+/// Frame control octet. The meaning of bits 6 and 4 depends on the direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FCtrl(pub u8);
+
+impl FCtrl {
+    pub fn adr(&self) -> bool {
+        self.0 & 0x80 != 0
+    }
+    // uplink only
+    pub fn adr_ack_req(&self) -> bool {
+        self.0 & 0x40 != 0
+    }
+    pub fn ack(&self) -> bool {
+        self.0 & 0x20 != 0
+    }
+    // uplink only
+    pub fn class_b(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+    // downlink only
+    pub fn f_pending(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+    pub fn fopts_len(&self) -> usize {
+        (self.0 & 0x0F) as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    pub join_eui: Eui64,
+    pub dev_eui: Eui64,
+    pub dev_nonce: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataHeader<'a> {
+    pub dev_addr: DevAddr,
+    pub fctrl: FCtrl,
+    pub fcnt: u16,
+    pub fopts: &'a [u8],
+    pub fport: Option<u8>,
+    pub frm_payload: &'a [u8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacPayload<'a> {
+    JoinRequest(JoinRequest),
+    // join-accepts are encrypted as a whole
+    JoinAccept(&'a [u8]),
+    Data(DataHeader<'a>),
+    RejoinRequest(&'a [u8]),
+    Proprietary(&'a [u8]),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhyPayload<'a> {
+    mhdr: u8,
+    payload: MacPayload<'a>,
+    mic: [u8; MIC_LEN],
+}
+
+impl<'a> PhyPayload<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<PhyPayload<'a>> {
+        let mhdr = *bytes.first().ok_or(Error::Empty)?;
+        let mtype = MType::from_mhdr(mhdr);
+        let major = mhdr & 0x03;
+        // proprietary frames may use any major version
+        if mtype != MType::Proprietary && major != 0 {
+            return Err(Error::UnsupportedMajor(major));
+        }
+
+        let len = bytes.len();
+        let invalid_length = Error::InvalidLength { mtype, len };
+        let min_len = match mtype {
+            MType::JoinRequest => JOIN_REQUEST_LEN,
+            MType::JoinAccept => JOIN_ACCEPT_LEN,
+            MType::RejoinRequest | MType::Proprietary => MHDR_LEN + MIC_LEN,
+            _ => DATA_MIN_LEN,
+        };
+        if len < min_len {
+            return Err(invalid_length);
+        }
+
+        let body = &bytes[MHDR_LEN..len - MIC_LEN];
+        let payload = match mtype {
+            MType::JoinRequest => {
+                if len != JOIN_REQUEST_LEN {
+                    return Err(invalid_length);
+                }
+                MacPayload::JoinRequest(JoinRequest {
+                    join_eui: Eui64::from_le(&body[0..8]),
+                    dev_eui: Eui64::from_le(&body[8..16]),
+                    dev_nonce: u16::from_le_bytes([body[16], body[17]]),
+                })
+            }
+            MType::JoinAccept => {
+                if len != JOIN_ACCEPT_LEN && len != JOIN_ACCEPT_CFLIST_LEN {
+                    return Err(invalid_length);
+                }
+                MacPayload::JoinAccept(body)
+            }
+            MType::RejoinRequest => MacPayload::RejoinRequest(body),
+            MType::Proprietary => MacPayload::Proprietary(body),
+            _ => {
+                let fctrl = FCtrl(body[4]);
+                let fhdr_len = FHDR_MIN_LEN + fctrl.fopts_len();
+                if body.len() < fhdr_len {
+                    return Err(Error::InvalidFOptsLen {
+                        fopts_len: fctrl.fopts_len(),
+                        len,
+                    });
+                }
+                let (fport, frm_payload) = match body.get(fhdr_len) {
+                    Some(fport) => (Some(*fport), &body[fhdr_len + 1..]),
+                    None => (None, &body[fhdr_len..]),
+                };
+                MacPayload::Data(DataHeader {
+                    dev_addr: DevAddr::from_le(&body[0..4]),
+                    fctrl,
+                    fcnt: u16::from_le_bytes([body[5], body[6]]),
+                    fopts: &body[FHDR_MIN_LEN..fhdr_len],
+                    fport,
+                    frm_payload,
+                })
+            }
+        };
+
+        let mut mic = [0; MIC_LEN];
+        mic.copy_from_slice(&bytes[len - MIC_LEN..]);
+        Ok(PhyPayload { mhdr, payload, mic })
+    }
+
+    pub fn mtype(&self) -> MType {
+        MType::from_mhdr(self.mhdr)
+    }
+
+    pub fn major(&self) -> u8 {
+        self.mhdr & 0x03
+    }
+
+    pub fn payload(&self) -> &MacPayload<'a> {
+        &self.payload
+    }
+
+    pub fn mic(&self) -> [u8; MIC_LEN] {
+        self.mic
+    }
+
+    pub fn data_header(&self) -> Option<&DataHeader<'a>> {
+        match &self.payload {
+            MacPayload::Data(header) => Some(header),
+            _ => None,
+        }
+    }
+
+    pub fn join_request(&self) -> Option<&JoinRequest> {
+        match &self.payload {
+            MacPayload::JoinRequest(join_request) => Some(join_request),
+            _ => None,
+        }
+    }
+
+    pub fn dev_addr(&self) -> Option<DevAddr> {
+        self.data_header().map(|header| header.dev_addr)
+    }
+
+    pub fn fctrl(&self) -> Option<FCtrl> {
+        self.data_header().map(|header| header.fctrl)
+    }
+
+    pub fn fcnt(&self) -> Option<u16> {
+        self.data_header().map(|header| header.fcnt)
+    }
+
+    pub fn fport(&self) -> Option<u8> {
+        self.data_header().and_then(|header| header.fport)
+    }
+
+    pub fn join_eui(&self) -> Option<Eui64> {
+        self.join_request()
+            .map(|join_request| join_request.join_eui)
+    }
+
+    pub fn dev_eui(&self) -> Option<Eui64> {
+        self.join_request().map(|join_request| join_request.dev_eui)
+    }
+}
+
+impl crate::push_data::RxPk {
+    pub fn phy_payload(&self) -> Result<PhyPayload<'_>> {
+        PhyPayload::parse(self.data())
+    }
+}
+
+impl crate::pull_resp::TxPk {
+    pub fn phy_payload(&self) -> Result<PhyPayload<'_>> {
+        PhyPayload::parse(self.data.data())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unconfirmed_data_up() {
+        let bytes =
+            hex::decode("402eb9af0100e30f02687ecbc867ffdfe771ceb5e491f12c4427176c53").unwrap();
+        let phy = PhyPayload::parse(&bytes).unwrap();
+        assert_eq!(phy.mtype(), MType::UnconfirmedDataUp);
+        assert!(phy.mtype().is_uplink());
+        assert_eq!(phy.major(), 0);
+        assert_eq!(phy.dev_addr(), Some(DevAddr(0x01AF_B92E)));
+        assert_eq!(phy.fcnt(), Some(0x0FE3));
+        assert_eq!(phy.fport(), Some(2));
+        assert_eq!(phy.fctrl().unwrap().fopts_len(), 0);
+        assert_eq!(phy.data_header().unwrap().frm_payload.len(), 16);
+        assert_eq!(phy.mic(), [0x27, 0x17, 0x6c, 0x53]);
+        assert_eq!(phy.dev_addr().unwrap().to_string(), "01AFB92E");
+    }
+
+    #[test]
+    fn data_with_fopts_and_no_fport() {
+        // ADR | ACK | FOptsLen = 2, no FPort
+        let bytes = hex::decode("6004030201a2010002030aaaaaaa").unwrap();
+        let phy = PhyPayload::parse(&bytes).unwrap();
+        assert_eq!(phy.mtype(), MType::UnconfirmedDataDown);
+        let header = phy.data_header().unwrap();
+        assert!(header.fctrl.adr() && header.fctrl.ack());
+        assert_eq!(header.fopts, &[0x02, 0x03]);
+        assert_eq!(header.fport, None);
+        assert!(header.frm_payload.is_empty());
+    }
+
+    #[test]
+    fn join_request() {
+        let bytes = hex::decode("000807060504030201efcdab8967452301341211223344").unwrap();
+        let phy = PhyPayload::parse(&bytes).unwrap();
+        assert_eq!(phy.mtype(), MType::JoinRequest);
+        assert_eq!(phy.join_eui(), Some(Eui64(0x0102_0304_0506_0708)));
+        assert_eq!(phy.dev_eui(), Some(Eui64(0x0123_4567_89AB_CDEF)));
+        assert_eq!(phy.join_request().unwrap().dev_nonce, 0x1234);
+        assert_eq!(phy.dev_addr(), None);
+    }
+
+    #[test]
+    fn malformed_frames() {
+        assert_eq!(PhyPayload::parse(&[]), Err(Error::Empty));
+        assert_eq!(
+            PhyPayload::parse(&[0x40, 0, 0, 0, 0]),
+            Err(Error::InvalidLength {
+                mtype: MType::UnconfirmedDataUp,
+                len: 5
+            })
+        );
+        assert_eq!(
+            PhyPayload::parse(&[0x41; 12]),
+            Err(Error::UnsupportedMajor(1))
+        );
+        // FOptsLen of 15 with no room for the options
+        let bytes = hex::decode("40040302010f0100aaaaaaaa").unwrap();
+        assert!(matches!(
+            PhyPayload::parse(&bytes),
+            Err(Error::InvalidFOptsLen { fopts_len: 15, .. })
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+/*
+   Counters and gauges instrumenting the runtimes. Without the `metrics` feature they are
+   zero-sized and updating them compiles to nothing.
+*/
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+    #[derive(Debug, Default)]
+    pub struct Counter(AtomicU64);
+
+    impl Counter {
+        pub fn inc(&self) {
+            self.add(1)
+        }
+
+        pub fn add(&self, n: u64) {
+            self.0.fetch_add(n, Ordering::Relaxed);
+        }
+
+        pub fn get(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct Gauge(AtomicI64);
+
+    impl Gauge {
+        pub fn set(&self, value: i64) {
+            self.0.store(value, Ordering::Relaxed);
+        }
+
+        pub fn get(&self) -> i64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    #[derive(Debug, Default)]
+    pub struct Counter;
+
+    impl Counter {
+        pub fn inc(&self) {}
+
+        #[allow(dead_code)] // not used by the client runtime
+        pub fn add(&self, _n: u64) {}
+    }
+
+    #[derive(Debug, Default)]
+    pub struct Gauge;
+
+    impl Gauge {
+        pub fn set(&self, _value: i64) {}
+    }
+}
+
+pub(crate) use imp::{Counter, Gauge};
+
+#[cfg(feature = "metrics")]
+pub(crate) use encoder::TextEncoder;
+
+#[cfg(feature = "metrics")]
+mod encoder {
+    use std::fmt::{Display, Write};
+
+    // Writes the Prometheus text exposition format
+    #[derive(Debug, Default)]
+    pub struct TextEncoder {
+        out: String,
+    }
+
+    impl TextEncoder {
+        pub fn header(&mut self, name: &str, kind: &str, help: &str) {
+            let _ = writeln!(self.out, "# HELP {name} {help}");
+            let _ = writeln!(self.out, "# TYPE {name} {kind}");
+        }
+
+        pub fn sample<V: Display>(
+            &mut self,
+            name: &str,
+            labels: &[(&str, &dyn Display)],
+            value: V,
+        ) {
+            self.out.push_str(name);
+            if !labels.is_empty() {
+                self.out.push('{');
+                for (i, (label, label_value)) in labels.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push(',');
+                    }
+                    let escaped = label_value
+                        .to_string()
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    let _ = write!(self.out, "{label}=\"{escaped}\"");
+                }
+                self.out.push('}');
+            }
+            let _ = writeln!(self.out, " {value}");
+        }
+
+        // `buckets` holds the count of each bucket, not cumulated, with one more entry
+        // than `bounds` for the observations above every bound
+        #[allow(dead_code)] // not used by the client runtime
+        pub fn histogram(
+            &mut self,
+            name: &str,
+            help: &str,
+            bounds: &[f64],
+            buckets: &[u64],
+            sum: f64,
+        ) {
+            self.header(name, "histogram", help);
+            let bucket = format!("{name}_bucket");
+            let mut cumulated = 0;
+            for (bound, count) in bounds.iter().zip(buckets) {
+                cumulated += count;
+                self.sample(&bucket, &[("le", bound)], cumulated);
+            }
+            let count: u64 = buckets.iter().sum();
+            self.sample(&bucket, &[("le", &"+Inf")], count);
+            self.sample(&format!("{name}_sum"), &[], sum);
+            self.sample(&format!("{name}_count"), &[], count);
+        }
+
+        pub fn finish(self) -> String {
+            self.out
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn text_format() {
+            let mut encoder = TextEncoder::default();
+            encoder.header("frames_total", "counter", "Frames received");
+            encoder.sample(
+                "frames_total",
+                &[("type", &"push_data"), ("gw", &"a\"b")],
+                3,
+            );
+            encoder.histogram("latency_seconds", "Latency", &[0.01, 0.1], &[1, 0, 2], 0.5);
+            assert_eq!(
+                encoder.finish(),
+                "# HELP frames_total Frames received\n\
+                 # TYPE frames_total counter\n\
+                 frames_total{type=\"push_data\",gw=\"a\\\"b\"} 3\n\
+                 # HELP latency_seconds Latency\n\
+                 # TYPE latency_seconds histogram\n\
+                 latency_seconds_bucket{le=\"0.01\"} 1\n\
+                 latency_seconds_bucket{le=\"0.1\"} 1\n\
+                 latency_seconds_bucket{le=\"+Inf\"} 3\n\
+                 latency_seconds_sum 0.5\n\
+                 latency_seconds_count 3\n"
+            );
+        }
+    }
+}
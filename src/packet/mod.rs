@@ -79,6 +79,22 @@ impl Up {
             Up::TxAck(tx_ack) => tx_ack.gateway_mac = mac,
         }
     }
+
+    pub fn gateway_mac(&self) -> MacAddress {
+        match self {
+            Up::PushData(push_data) => push_data.gateway_mac,
+            Up::PullData(pull_data) => pull_data.gateway_mac,
+            Up::TxAck(tx_ack) => tx_ack.gateway_mac,
+        }
+    }
+
+    pub fn identifier(&self) -> Identifier {
+        match self {
+            Up::PushData(_) => Identifier::PushData,
+            Up::PullData(_) => Identifier::PullData,
+            Up::TxAck(_) => Identifier::TxAck,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
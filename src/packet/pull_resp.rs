@@ -119,7 +119,7 @@ pub struct Time {
     #[serde(skip_serializing_if = "Option::is_none")]
     tmst: Option<Tmst>, // Send packet on a certain timestamp value (will ignore time)
     #[serde(skip_serializing_if = "Option::is_none")]
-    tmms: Option<Tmst>, // Send packet at a certain GPS time (GPS synchronization required)
+    tmms: Option<u64>, // Send packet at a certain GPS time (GPS synchronization required)
 }
 
 impl Time {
@@ -135,12 +135,8 @@ impl Time {
         }
     }
 
-    pub fn tmms(&self) -> Option<u32> {
-        if let Some(Tmst::Tmst(val)) = self.tmms {
-            Some(val)
-        } else {
-            None
-        }
+    pub fn tmms(&self) -> Option<u64> {
+        self.tmms
     }
 
     pub fn immediate() -> Time {
@@ -159,11 +155,12 @@ impl Time {
         }
     }
 
-    pub fn by_tmms(tmms: u32) -> Time {
+    // tmms is milliseconds since the GPS epoch, which does not fit in 32 bits
+    pub fn by_tmms(tmms: u64) -> Time {
         Time {
             imme: false,
             tmst: None,
-            tmms: Some(Tmst::Tmst(tmms)),
+            tmms: Some(tmms),
         }
     }
 }
@@ -0,0 +1,179 @@
+/*
+   Decides which gateways the server runtime talks to. The policy is consulted for every
+   parsed frame before it is ACKed; rejected frames are neither ACKed nor processed.
+*/
+use super::MacAddress;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+// a MAC unknown to the policy is reported at most once per source address in this interval,
+// so that frames spoofing it cannot flood the event queue
+pub(crate) const UNKNOWN_GATEWAY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    // a known gateway sending from an address it is not allowed to use
+    Reject,
+    // a MAC unknown to the policy; the frame is rejected and reported as Event::UnknownGateway,
+    // once per source address every UNKNOWN_GATEWAY_REPORT_INTERVAL
+    Unknown,
+}
+
+pub trait AdmissionPolicy: fmt::Debug + Send + Sync {
+    fn admit(&self, mac: MacAddress, addr: SocketAddr) -> Admission;
+}
+
+// accepts every gateway, which is the behaviour without a policy
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl AdmissionPolicy for AllowAll {
+    fn admit(&self, _mac: MacAddress, _addr: SocketAddr) -> Admission {
+        Admission::Accept
+    }
+}
+
+/// Accepts the listed gateways only, each optionally pinned to address ranges
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    gateways: HashMap<MacAddress, Vec<IpCidr>>,
+}
+
+impl Allowlist {
+    /// allows the gateway from any address
+    pub fn allow(mut self, mac: MacAddress) -> Self {
+        self.gateways.entry(mac).or_default();
+        self
+    }
+
+    /// allows the gateway from addresses in the range; may be repeated for several ranges
+    pub fn pin(mut self, mac: MacAddress, cidr: IpCidr) -> Self {
+        self.gateways.entry(mac).or_default().push(cidr);
+        self
+    }
+}
+
+impl AdmissionPolicy for Allowlist {
+    fn admit(&self, mac: MacAddress, addr: SocketAddr) -> Admission {
+        match self.gateways.get(&mac) {
+            None => Admission::Unknown,
+            Some(pins) if pins.is_empty() => Admission::Accept,
+            Some(pins) if pins.iter().any(|cidr| cidr.contains(addr.ip())) => Admission::Accept,
+            Some(_) => Admission::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid CIDR: {0}")]
+pub struct InvalidCidr(String);
+
+impl IpCidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<IpCidr, InvalidCidr> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(InvalidCidr(format!("{addr}/{prefix_len}")));
+        }
+        Ok(IpCidr { addr, prefix_len })
+    }
+
+    // IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, match IPv4 ranges
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_eq(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_eq(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = (prefix_len as usize / 8, prefix_len % 8);
+    if net[..bytes] != addr[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ addr[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for IpCidr {
+    type Err = InvalidCidr;
+
+    // "10.0.0.0/8", or a single address
+    fn from_str(s: &str) -> Result<IpCidr, InvalidCidr> {
+        let invalid = || InvalidCidr(s.to_string());
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => IpCidr::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix_len.parse().map_err(|_| invalid())?,
+            ),
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| invalid())?;
+                IpCidr::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: IpCidr = "10.1.0.0/12".parse().unwrap();
+        assert!(cidr.contains("10.15.255.1".parse().unwrap()));
+        assert!(!cidr.contains("10.16.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("2001:db8::1".parse().unwrap()));
+        let v6: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert_eq!(
+            "192.168.1.1".parse::<IpCidr>().unwrap().to_string(),
+            "192.168.1.1/32"
+        );
+    }
+
+    #[test]
+    fn allowlist() {
+        let (open, pinned) = (MacAddress::from([1; 8]), MacAddress::from([2; 8]));
+        let policy = Allowlist::default()
+            .allow(open)
+            .pin(pinned, "192.168.0.0/16".parse().unwrap());
+        let lan: SocketAddr = "192.168.4.2:1680".parse().unwrap();
+        let wan: SocketAddr = "203.0.113.9:1680".parse().unwrap();
+        assert_eq!(policy.admit(open, wan), Admission::Accept);
+        assert_eq!(policy.admit(pinned, lan), Admission::Accept);
+        assert_eq!(policy.admit(pinned, wan), Admission::Reject);
+        assert_eq!(
+            policy.admit(MacAddress::from([3; 8]), lan),
+            Admission::Unknown
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use super::{AdmissionPolicy, DedupConfig, Error, RateLimit, Result};
+use crate::duration::{millis, option_millis};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+const DEFAULT_DISCONNECT_THRESHOLD: u64 = 60;
+const DEFAULT_CACHE_CHECK_FREQ: u64 = 60;
+const DEFAULT_QUEUE_SIZE: usize = 100;
+const DEFAULT_UPLINK_HISTORY: u64 = 60;
+const DEFAULT_PENDING_ACK_EXPIRY: u64 = 30;
+const DEFAULT_MAC_CONFLICT_WINDOW: u64 = 60;
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// What to do with an event when the client's event queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// wait for the client to make room; this stalls all UDP processing
+    #[default]
+    Block,
+    /// discard the event which did not fit
+    DropNewest,
+    /// discard the oldest queued event to make room
+    DropOldest,
+    /// discard the oldest queued stat event, or the oldest event if no stat is queued
+    DropStatsFirst,
+}
+
+/// Which address a gateway MAC claimed from several addresses is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacConflictPolicy {
+    /// the address which claimed the MAC first within the window
+    FirstWins,
+    /// the address of the latest PULL_DATA
+    #[default]
+    LatestWins,
+    /// neither: the gateway is disconnected until the conflict ends
+    Quarantine,
+}
+
+// Durations are (de)serialized as milliseconds, with the field names suffixed by `_ms`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Time without PULL_DATA after which a gateway is disconnected; `None` keeps them forever
+    #[serde(rename = "disconnect_threshold_ms", with = "option_millis")]
+    pub disconnect_threshold: Option<Duration>,
+    /// How often disconnected gateways and expired state are cleaned up
+    #[serde(rename = "cache_check_freq_ms", with = "millis")]
+    pub cache_check_freq: Duration,
+    /// Size of the queue feeding the internal task, shared by UDP frames and downlink requests
+    pub internal_queue_size: usize,
+    /// Size of the queue of events delivered to the client
+    pub event_queue_size: usize,
+    pub event_overflow: OverflowPolicy,
+    /// Largest UDP datagram accepted and sent
+    pub max_message_size: usize,
+    /// TX_ACK timeout used when `dispatch` or `send` are called without one
+    #[serde(rename = "ack_timeout_ms", with = "option_millis")]
+    pub ack_timeout: Option<Duration>,
+    /// How long a sent downlink waits for its TX_ACK before it fails with `Error::AckTimeout`
+    #[serde(rename = "pending_ack_expiry_ms", with = "millis")]
+    pub pending_ack_expiry: Duration,
+    /// SO_RCVBUF and SO_SNDBUF of the server socket; `None` keeps the OS default
+    pub socket_recv_buffer_size: Option<usize>,
+    pub socket_send_buffer_size: Option<usize>,
+    pub dedup: Option<DedupConfig>,
+    /// How long uplink metadata is kept for `send_to_device`
+    #[serde(rename = "uplink_history_ms", with = "millis")]
+    pub uplink_history: Duration,
+    pub mac_conflict: MacConflictPolicy,
+    /// A MAC whose address changes more than once within the window is in conflict
+    #[serde(rename = "mac_conflict_window_ms", with = "millis")]
+    pub mac_conflict_window: Duration,
+    /// Frames accepted from each source address, checked before parsing
+    pub source_rate_limit: Option<RateLimit>,
+    /// Frames accepted from each gateway MAC, checked before ACKing
+    pub gateway_rate_limit: Option<RateLimit>,
+    /// `UnableToParseUdpFrame` events emitted, over all sources
+    pub parse_error_rate_limit: Option<RateLimit>,
+    /// Gateways allowed to use the server; `None` accepts every gateway
+    #[serde(skip)]
+    pub admission: Option<Arc<dyn AdmissionPolicy>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            disconnect_threshold: Some(Duration::from_secs(DEFAULT_DISCONNECT_THRESHOLD)),
+            cache_check_freq: Duration::from_secs(DEFAULT_CACHE_CHECK_FREQ),
+            internal_queue_size: DEFAULT_QUEUE_SIZE,
+            event_queue_size: DEFAULT_QUEUE_SIZE,
+            event_overflow: OverflowPolicy::default(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            ack_timeout: None,
+            pending_ack_expiry: Duration::from_secs(DEFAULT_PENDING_ACK_EXPIRY),
+            socket_recv_buffer_size: None,
+            socket_send_buffer_size: None,
+            dedup: None,
+            uplink_history: Duration::from_secs(DEFAULT_UPLINK_HISTORY),
+            mac_conflict: MacConflictPolicy::default(),
+            mac_conflict_window: Duration::from_secs(DEFAULT_MAC_CONFLICT_WINDOW),
+            source_rate_limit: None,
+            gateway_rate_limit: None,
+            parse_error_rate_limit: None,
+            admission: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    // rejects values the runtime cannot be built with, such as zero-sized queues
+    pub fn validate(&self) -> Result {
+        let invalid = |reason| Err(Error::InvalidConfig(reason));
+        if self.internal_queue_size == 0 {
+            return invalid("internal_queue_size must be greater than 0");
+        }
+        if self.event_queue_size == 0 {
+            return invalid("event_queue_size must be greater than 0");
+        }
+        if self.cache_check_freq.is_zero() {
+            return invalid("cache_check_freq_ms must be greater than 0");
+        }
+        if self.max_message_size == 0 {
+            return invalid("max_message_size must be greater than 0");
+        }
+        if self.dedup.is_some_and(|dedup| dedup.window.is_zero()) {
+            return invalid("dedup window_ms must be greater than 0");
+        }
+        for limit in [
+            self.source_rate_limit,
+            self.gateway_rate_limit,
+            self.parse_error_rate_limit,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !limit.is_valid() {
+                return invalid("rate limits need a per_second and a burst greater than 0");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn disconnect_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.disconnect_threshold = threshold;
+        self
+    }
+
+    pub fn cache_check_freq(mut self, freq: Duration) -> Self {
+        self.cache_check_freq = freq;
+        self
+    }
+
+    pub fn queue_sizes(mut self, internal: usize, events: usize) -> Self {
+        self.internal_queue_size = internal;
+        self.event_queue_size = events;
+        self
+    }
+
+    pub fn event_overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.event_overflow = policy;
+        self
+    }
+
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    pub fn ack_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    pub fn pending_ack_expiry(mut self, expiry: Duration) -> Self {
+        self.pending_ack_expiry = expiry;
+        self
+    }
+
+    pub fn socket_buffer_sizes(mut self, recv: Option<usize>, send: Option<usize>) -> Self {
+        self.socket_recv_buffer_size = recv;
+        self.socket_send_buffer_size = send;
+        self
+    }
+
+    pub fn dedup(mut self, dedup: Option<DedupConfig>) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn uplink_history(mut self, retention: Duration) -> Self {
+        self.uplink_history = retention;
+        self
+    }
+
+    pub fn mac_conflict(mut self, policy: MacConflictPolicy, window: Duration) -> Self {
+        self.mac_conflict = policy;
+        self.mac_conflict_window = window;
+        self
+    }
+
+    pub fn rate_limits(
+        mut self,
+        source: Option<RateLimit>,
+        gateway: Option<RateLimit>,
+        parse_errors: Option<RateLimit>,
+    ) -> Self {
+        self.source_rate_limit = source;
+        self.gateway_rate_limit = gateway;
+        self.parse_error_rate_limit = parse_errors;
+        self
+    }
+
+    pub fn admission(mut self, policy: impl AdmissionPolicy + 'static) -> Self {
+        self.admission = Some(Arc::new(policy));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let json = "{\"disconnect_threshold_ms\":null,\"event_queue_size\":500,\"event_overflow\":\"drop_newest\",\"dedup\":{\"window_ms\":200}}";
+        let config: ServerConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.disconnect_threshold, None);
+        assert_eq!(config.event_queue_size, 500);
+        assert_eq!(config.internal_queue_size, DEFAULT_QUEUE_SIZE);
+        assert_eq!(config.event_overflow, OverflowPolicy::DropNewest);
+        let dedup = config.dedup.unwrap();
+        assert_eq!(dedup.window, Duration::from_millis(200));
+        assert_eq!(dedup.late_retention, Duration::from_secs(2));
+
+        let roundtrip: ServerConfig =
+            serde_json::from_str(&serde_json::to_string(&ServerConfig::default()).unwrap())
+                .unwrap();
+        assert_eq!(
+            roundtrip.cache_check_freq,
+            Duration::from_secs(DEFAULT_CACHE_CHECK_FREQ)
+        );
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert!(ServerConfig::default().validate().is_ok());
+        for json in [
+            "{\"internal_queue_size\":0}",
+            "{\"event_queue_size\":0}",
+            "{\"cache_check_freq_ms\":0}",
+            "{\"dedup\":{\"window_ms\":0}}",
+            "{\"source_rate_limit\":{\"per_second\":10.0,\"burst\":0}}",
+            "{\"gateway_rate_limit\":{\"per_second\":0.0,\"burst\":10}}",
+            "{\"parse_error_rate_limit\":{\"per_second\":-1.0,\"burst\":10}}",
+        ] {
+            let config: ServerConfig = serde_json::from_str(json).unwrap();
+            assert!(matches!(config.validate(), Err(Error::InvalidConfig(_))));
+        }
+        let nan = RateLimit::new(f64::NAN, 10);
+        let config = ServerConfig::default().rate_limits(None, Some(nan), None);
+        assert!(matches!(config.validate(), Err(Error::InvalidConfig(_))));
+        // late retention saturates instead of overflowing
+        assert_eq!(
+            DedupConfig::new(Duration::MAX).late_retention,
+            Duration::MAX
+        );
+    }
+}
@@ -0,0 +1,98 @@
+/*
+   Detects a gateway MAC claimed from several addresses: a gateway whose address changes
+   once has moved, one whose address keeps changing within the window is in conflict.
+*/
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+pub(crate) struct AddressHistory {
+    // first and last PULL_DATA from each address within the window
+    seen: HashMap<SocketAddr, (Instant, Instant)>,
+    last: SocketAddr,
+    // times the MAC moved to another address within the window
+    moves: VecDeque<Instant>,
+}
+
+impl AddressHistory {
+    pub fn new(addr: SocketAddr, now: Instant) -> AddressHistory {
+        AddressHistory {
+            seen: HashMap::from([(addr, (now, now))]),
+            last: addr,
+            moves: VecDeque::new(),
+        }
+    }
+
+    // records a PULL_DATA and returns the competing addresses, first seen first,
+    // while the MAC has moved more than once within the window
+    pub fn observe(
+        &mut self,
+        addr: SocketAddr,
+        now: Instant,
+        window: Duration,
+    ) -> Option<Vec<SocketAddr>> {
+        self.expire(now, window);
+        if addr != self.last {
+            self.moves.push_back(now);
+            self.last = addr;
+        }
+        self.seen
+            .entry(addr)
+            .and_modify(|(_, last)| *last = now)
+            .or_insert((now, now));
+        if self.moves.len() < 2 {
+            return None;
+        }
+        let mut addrs: Vec<_> = self
+            .seen
+            .iter()
+            .map(|(addr, seen)| (seen.0, *addr))
+            .collect();
+        addrs.sort();
+        Some(addrs.into_iter().map(|(_, addr)| addr).collect())
+    }
+
+    // forgets what is older than the window; true once nothing is left
+    pub fn expire(&mut self, now: Instant, window: Duration) -> bool {
+        self.seen
+            .retain(|_, (_, last)| now.duration_since(*last) <= window);
+        while self
+            .moves
+            .front()
+            .is_some_and(|moved| now.duration_since(*moved) > window)
+        {
+            self.moves.pop_front();
+        }
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn oscillation_is_a_conflict() {
+        let window = Duration::from_secs(60);
+        let (a, b): (SocketAddr, SocketAddr) = (
+            "192.0.2.1:1700".parse().unwrap(),
+            "198.51.100.7:1700".parse().unwrap(),
+        );
+        let now = Instant::now();
+        let mut history = AddressHistory::new(a, now);
+        assert_eq!(history.observe(a, now, window), None);
+        // a single move is a gateway changing address
+        let now = now + Duration::from_secs(5);
+        assert_eq!(history.observe(b, now, window), None);
+        let now = now + Duration::from_secs(5);
+        assert_eq!(history.observe(a, now, window), Some(vec![a, b]));
+        assert_eq!(history.observe(a, now, window), Some(vec![a, b]));
+
+        // the conflict ends once the window has passed without moves
+        let now = now + Duration::from_secs(61);
+        assert_eq!(history.observe(a, now, window), None);
+        assert!(history.expire(now + Duration::from_secs(61), window));
+    }
+}
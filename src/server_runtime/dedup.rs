@@ -0,0 +1,191 @@
+use super::{MacAddress, RxPk};
+use crate::duration::{millis, option_millis};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "RawDedupConfig")]
+pub struct DedupConfig {
+    /// How long to wait for copies of a frame after the first one is heard
+    #[serde(rename = "window_ms", with = "millis")]
+    pub window: Duration,
+    /// How long a frame is remembered after its window closes, so that
+    /// late copies are reported as `Event::LateDuplicate`
+    #[serde(rename = "late_retention_ms", with = "millis")]
+    pub late_retention: Duration,
+}
+
+// late_retention defaults to a multiple of the window, which serde(default) cannot express
+#[derive(Deserialize)]
+struct RawDedupConfig {
+    #[serde(rename = "window_ms", with = "millis")]
+    window: Duration,
+    #[serde(rename = "late_retention_ms", default, with = "option_millis")]
+    late_retention: Option<Duration>,
+}
+
+impl From<RawDedupConfig> for DedupConfig {
+    fn from(raw: RawDedupConfig) -> DedupConfig {
+        let mut config = DedupConfig::new(raw.window);
+        if let Some(late_retention) = raw.late_retention {
+            config.late_retention = late_retention;
+        }
+        config
+    }
+}
+
+impl DedupConfig {
+    pub fn new(window: Duration) -> DedupConfig {
+        DedupConfig {
+            window,
+            late_retention: window.saturating_mul(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UplinkCopy {
+    pub gateway_mac: MacAddress,
+    pub rxpk: RxPk,
+}
+
+// A frame heard by one or more gateways within the dedup window.
+// Copies are sorted by signal quality, best first.
+#[derive(Debug, Clone)]
+pub struct DedupedPacket {
+    pub payload: Vec<u8>,
+    pub copies: Vec<UplinkCopy>,
+}
+
+impl DedupedPacket {
+    pub fn best(&self) -> &UplinkCopy {
+        // a DedupedPacket is only ever created with at least one copy
+        &self.copies[0]
+    }
+
+    fn sort_by_signal_quality(&mut self) {
+        self.copies.sort_by(|a, b| {
+            b.rxpk
+                .snr()
+                .total_cmp(&a.rxpk.snr())
+                .then(b.rxpk.channel_rssi().cmp(&a.rxpk.channel_rssi()))
+        });
+    }
+}
+
+pub(crate) enum Insert {
+    // first copy of a frame: the caller should close the window after `window`
+    First(u64),
+    Duplicate,
+    Late(RxPk, MacAddress),
+}
+
+pub(crate) struct Deduplicator {
+    config: DedupConfig,
+    pending: HashMap<u64, DedupedPacket>,
+    closed: HashMap<u64, Instant>,
+}
+
+impl Deduplicator {
+    pub fn new(config: DedupConfig) -> Deduplicator {
+        Deduplicator {
+            config,
+            pending: HashMap::new(),
+            closed: HashMap::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.config.window
+    }
+
+    pub fn insert(&mut self, rxpk: RxPk, gateway_mac: MacAddress, now: Instant) -> Insert {
+        let key = payload_hash(rxpk.data());
+        if let Some(packet) = self.pending.get_mut(&key) {
+            packet.copies.push(UplinkCopy { gateway_mac, rxpk });
+            Insert::Duplicate
+        } else if self
+            .closed
+            .get(&key)
+            .is_some_and(|closed| now.duration_since(*closed) < self.config.late_retention)
+        {
+            Insert::Late(rxpk, gateway_mac)
+        } else {
+            self.pending.insert(
+                key,
+                DedupedPacket {
+                    payload: rxpk.data().clone(),
+                    copies: vec![UplinkCopy { gateway_mac, rxpk }],
+                },
+            );
+            Insert::First(key)
+        }
+    }
+
+    pub fn close(&mut self, key: u64, now: Instant) -> Option<DedupedPacket> {
+        let mut packet = self.pending.remove(&key)?;
+        self.closed.insert(key, now);
+        packet.sort_by_signal_quality();
+        Some(packet)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let retention = self.config.late_retention;
+        self.closed
+            .retain(|_, closed| now.duration_since(*closed) < retention);
+    }
+}
+
+fn payload_hash(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::push_data::Packet;
+
+    fn rxpk(snr: f32) -> RxPk {
+        let mut rxpk = Packet::random().data.rxpk.unwrap().pop().unwrap();
+        if let RxPk::V1(pk) = &mut rxpk {
+            pk.lsnr = snr;
+        }
+        rxpk
+    }
+
+    #[test]
+    fn aggregates_copies_by_signal_quality() {
+        let mut dedup = Deduplicator::new(DedupConfig::new(Duration::from_millis(200)));
+        let now = Instant::now();
+        let key = match dedup.insert(rxpk(-10.0), MacAddress::from([1; 8]), now) {
+            Insert::First(key) => key,
+            _ => panic!("expected first copy"),
+        };
+        assert!(matches!(
+            dedup.insert(rxpk(5.0), MacAddress::from([2; 8]), now),
+            Insert::Duplicate
+        ));
+
+        let packet = dedup.close(key, now).unwrap();
+        assert_eq!(packet.copies.len(), 2);
+        assert_eq!(packet.best().gateway_mac, MacAddress::from([2; 8]));
+
+        // copies arriving after the window are reported as late until they expire
+        assert!(matches!(
+            dedup.insert(rxpk(0.0), MacAddress::from([3; 8]), now),
+            Insert::Late(_, _)
+        ));
+        let later = now + Duration::from_secs(3);
+        dedup.expire(later);
+        assert!(matches!(
+            dedup.insert(rxpk(0.0), MacAddress::from([3; 8]), later),
+            Insert::First(_)
+        ));
+    }
+}
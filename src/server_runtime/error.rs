@@ -1,4 +1,4 @@
-use super::{Event, InternalEvent, SystemTime};
+use super::{Event, InternalEvent};
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 
@@ -8,10 +8,16 @@ pub enum Error {
     Ack(#[from] crate::packet::tx_ack::Error),
     #[error("Send has timed out")]
     SendTimeout,
+    #[error("Downlink was sent but the gateway never acknowledged it")]
+    AckTimeout,
+    #[error("Downlink could not be sent to the gateway")]
+    NotSent,
     #[error("Dispatch called with no packet")]
     DispatchWithNoSendPacket,
     #[error("Client requested to transmit to unknown MAC")]
     UnknownMac,
+    #[error("No gateway has recently heard from the device")]
+    NoGatewayForDevice,
     #[error("Io Error from using UDP: {0}")]
     UdpError(#[from] std::io::Error),
     #[error("ClientEventQueue Full: {0}")]
@@ -24,13 +30,25 @@ pub enum Error {
     AckRecv,
     #[error("error sending ACK")]
     AckSend,
+    #[error("Invalid server config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("Server runtime has shut down")]
+    Shutdown,
     #[error("Join error: {0}")]
     Join(#[from] tokio::task::JoinError),
-    #[error("Client was last seen in the future ({last_seen:?}) compared to now ({now:?})")]
-    LastSeen {
-        last_seen: SystemTime,
-        now: SystemTime,
-    },
+}
+
+impl Error {
+    // errors for which another gateway may still be able to deliver the downlink
+    pub fn should_try_next_gateway(&self) -> bool {
+        matches!(
+            self,
+            Error::Ack(crate::packet::tx_ack::Error::TooLate)
+                | Error::Ack(crate::packet::tx_ack::Error::CollisionPacket)
+                | Error::UnknownMac
+                | Error::NotSent
+        )
+    }
 }
 
 impl From<tokio::time::error::Elapsed> for Error {
@@ -0,0 +1,253 @@
+/*
+   Bounded queue of events from Internal to ClientRx. Unlike an mpsc channel, the producer
+   can evict queued events, which the drop-oldest and drop-stats-first policies need.
+*/
+use super::{Event, OverflowPolicy};
+use crate::sync::lock;
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, MutexGuard,
+};
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct State {
+    events: VecDeque<Event>,
+    // the runtime has stopped: no more events will be pushed
+    sender_closed: bool,
+    // ClientRx was dropped: events are discarded
+    receiver_closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    readable: Notify,
+    writable: Notify,
+    receiver_dropped: Notify,
+    dropped: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+pub(crate) fn channel(capacity: usize, policy: OverflowPolicy) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::default(),
+        capacity: capacity.max(1),
+        policy,
+        readable: Notify::new(),
+        writable: Notify::new(),
+        receiver_dropped: Notify::new(),
+        dropped: AtomicU64::new(0),
+    });
+    (
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventReceiver { shared },
+    )
+}
+
+#[derive(Debug)]
+pub(crate) struct EventSender {
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    pub async fn send(&self, event: Event) {
+        let mut event = Some(event);
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if state.receiver_closed {
+                    self.drop_event();
+                    return;
+                }
+                if state.events.len() < self.shared.capacity {
+                    state.events.extend(event.take());
+                    break;
+                }
+                match self.shared.policy {
+                    OverflowPolicy::Block => (),
+                    OverflowPolicy::DropNewest => {
+                        self.drop_event();
+                        return;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        state.events.pop_front();
+                        state.events.extend(event.take());
+                        self.drop_event();
+                        break;
+                    }
+                    OverflowPolicy::DropStatsFirst => {
+                        let evict = state
+                            .events
+                            .iter()
+                            .position(|queued| matches!(queued, Event::StatReceived(..)))
+                            .unwrap_or(0);
+                        state.events.remove(evict);
+                        state.events.extend(event.take());
+                        self.drop_event();
+                        break;
+                    }
+                }
+            }
+            self.shared.writable.notified().await;
+        }
+        self.shared.readable.notify_one();
+    }
+
+    // resolves once ClientRx has been dropped
+    pub async fn closed(&self) {
+        loop {
+            let dropped = self.shared.receiver_dropped.notified();
+            if self.shared.lock().receiver_closed {
+                return;
+            }
+            dropped.await;
+        }
+    }
+
+    fn drop_event(&self) {
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn monitor(&self) -> EventQueueMonitor {
+        EventQueueMonitor {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+// observes the queue without keeping either end open
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub(crate) struct EventQueueMonitor {
+    shared: Arc<Shared>,
+}
+
+#[cfg(feature = "metrics")]
+impl EventQueueMonitor {
+    pub fn len(&self) -> usize {
+        self.shared.lock().events.len()
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.shared.lock().sender_closed = true;
+        self.shared.readable.notify_one();
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+impl EventReceiver {
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if let Some(event) = state.events.pop_front() {
+                    drop(state);
+                    self.shared.writable.notify_one();
+                    return Some(event);
+                }
+                if state.sender_closed {
+                    return None;
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+
+    /// Events discarded because the queue was full
+    pub fn dropped_events(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiver_closed = true;
+        state.events.clear();
+        drop(state);
+        self.shared.writable.notify_one();
+        self.shared.receiver_dropped.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::push_data::Stat;
+    use crate::MacAddress;
+
+    fn stat() -> Event {
+        let stat = Stat {
+            time: String::new(),
+            lati: None,
+            long: None,
+            alti: None,
+            rxnb: 0,
+            rxok: 0,
+            rxfw: 0,
+            ackr: None,
+            dwnb: 0,
+            txnb: 0,
+            temp: None,
+        };
+        Event::StatReceived(stat, MacAddress::from([0; 8]))
+    }
+
+    fn client(n: u8) -> Event {
+        Event::NewClient((MacAddress::from([n; 8]), ([127, 0, 0, 1], 1680).into()))
+    }
+
+    fn is_client(event: Option<Event>, n: u8) -> bool {
+        matches!(event, Some(Event::NewClient((mac, _))) if mac == MacAddress::from([n; 8]))
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropOldest);
+        for n in 0..3 {
+            sender.send(client(n)).await;
+        }
+        assert_eq!(receiver.dropped_events(), 1);
+        assert!(is_client(receiver.recv().await, 1));
+        assert!(is_client(receiver.recv().await, 2));
+
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropStatsFirst);
+        sender.send(client(0)).await;
+        sender.send(stat()).await;
+        sender.send(client(1)).await;
+        sender.send(client(2)).await;
+        assert_eq!(receiver.dropped_events(), 2);
+        assert!(is_client(receiver.recv().await, 1));
+        assert!(is_client(receiver.recv().await, 2));
+
+        let (sender, mut receiver) = channel(1, OverflowPolicy::DropNewest);
+        sender.send(client(0)).await;
+        sender.send(client(1)).await;
+        drop(sender);
+        assert!(is_client(receiver.recv().await, 0));
+        assert!(receiver.recv().await.is_none());
+        assert_eq!(receiver.dropped_events(), 1);
+    }
+}
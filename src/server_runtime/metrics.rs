@@ -0,0 +1,302 @@
+use super::stats::latency_bucket;
+use super::{RxPk, ACK_LATENCY_BUCKETS_MS};
+use crate::metrics::{Counter, Gauge};
+use crate::{push_data::CRC, tx_ack};
+use std::time::Duration;
+
+// label of each TX_ACK result, in the order of ServerMetrics::tx_ack_results
+const TX_ACK_RESULTS: [&str; 11] = [
+    "ok",
+    "too_late",
+    "too_early",
+    "collision_packet",
+    "collision_beacon",
+    "tx_freq",
+    "tx_power",
+    "adjusted_tx_power",
+    "gps_unlocked",
+    "send_lbt",
+    "send_fail",
+];
+
+// Instruments UdpRx and Internal. Per-gateway counters are kept in GatewayStats, which are
+// dropped with the gateway; the totals over all gateways are kept here so that they never
+// go down.
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics {
+    pub push_data_frames: Counter,
+    pub pull_data_frames: Counter,
+    pub tx_ack_frames: Counter,
+    pub invalid_frames: Counter,
+    pub rejected_frames: Counter,
+    pub source_rate_limited_frames: Counter,
+    pub gateway_rate_limited_frames: Counter,
+    pub parse_errors_suppressed: Counter,
+    pub bytes_received: Counter,
+    pub push_acks_sent: Counter,
+    pub pull_acks_sent: Counter,
+    pub ack_send_errors: Counter,
+    pub downlink_send_failures: Counter,
+    pub downlinks_pending: Gauge,
+    pub rxpk_crc_ok: Counter,
+    pub rxpk_crc_fail: Counter,
+    pub rxpk_no_crc: Counter,
+    pub stat_objects: Counter,
+    pub downlinks_sent: Counter,
+    pub ack_timeouts: Counter,
+    pub tx_ack_results: [Counter; TX_ACK_RESULTS.len()],
+    pub ack_latency_buckets: [Counter; ACK_LATENCY_BUCKETS_MS.len() + 1],
+    pub ack_latency_sum_ms: Counter,
+}
+
+impl ServerMetrics {
+    pub fn rxpk(&self, rxpk: &RxPk) {
+        match rxpk.crc_status() {
+            CRC::OK => self.rxpk_crc_ok.inc(),
+            CRC::Fail => self.rxpk_crc_fail.inc(),
+            CRC::Disabled => self.rxpk_no_crc.inc(),
+        }
+    }
+
+    pub fn tx_ack(&self, result: &Result<Option<u32>, tx_ack::Error>, latency: Duration) {
+        let index = match result {
+            Ok(_) => 0,
+            Err(tx_ack::Error::TooLate) => 1,
+            Err(tx_ack::Error::TooEarly) => 2,
+            Err(tx_ack::Error::CollisionPacket) => 3,
+            Err(tx_ack::Error::CollisionBeacon) => 4,
+            Err(tx_ack::Error::InvalidTransmitFrequency) => 5,
+            Err(tx_ack::Error::InvalidTransmitPower(_)) => 6,
+            Err(tx_ack::Error::AdjustedTransmitPower(_, _)) => 7,
+            Err(tx_ack::Error::GpsUnlocked) => 8,
+            Err(tx_ack::Error::SendLBT) => 9,
+            Err(tx_ack::Error::SendFail) => 10,
+        };
+        self.tx_ack_results[index].inc();
+        let ms = latency.as_millis() as u64;
+        self.ack_latency_buckets[latency_bucket(ms)].inc();
+        self.ack_latency_sum_ms.add(ms);
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) use render::Snapshot;
+
+#[cfg(feature = "metrics")]
+mod render {
+    use super::{ServerMetrics, TX_ACK_RESULTS};
+    use crate::metrics::TextEncoder;
+    use crate::server_runtime::{GatewayStats, ACK_LATENCY_BUCKETS_MS};
+    use crate::MacAddress;
+    use std::collections::HashMap;
+
+    // metric name suffix, help and value
+    type GatewayCounter = (&'static str, &'static str, fn(&GatewayStats) -> u64);
+
+    // state owned by other parts of the runtime, gathered by ClientTx::render_metrics
+    pub struct Snapshot<'a> {
+        pub internal_queue_depth: usize,
+        pub event_queue_depth: usize,
+        pub events_dropped: u64,
+        pub gateways_connected: usize,
+        pub gateways: &'a HashMap<MacAddress, GatewayStats>,
+    }
+
+    impl ServerMetrics {
+        pub fn render(&self, snapshot: Snapshot) -> String {
+            let mut encoder = TextEncoder::default();
+
+            let name = "semtech_udp_server_frames_received_total";
+            encoder.header(name, "counter", "UDP frames received, by GWMP identifier");
+            for (frame_type, counter) in [
+                ("push_data", &self.push_data_frames),
+                ("pull_data", &self.pull_data_frames),
+                ("tx_ack", &self.tx_ack_frames),
+                ("invalid", &self.invalid_frames),
+            ] {
+                encoder.sample(name, &[("type", &frame_type)], counter.get());
+            }
+
+            let name = "semtech_udp_server_frames_rejected_total";
+            encoder.header(
+                name,
+                "counter",
+                "UDP frames rejected by the admission policy",
+            );
+            encoder.sample(name, &[], self.rejected_frames.get());
+
+            let name = "semtech_udp_server_frames_rate_limited_total";
+            encoder.header(name, "counter", "UDP frames dropped by a rate limit");
+            encoder.sample(
+                name,
+                &[("limit", &"source")],
+                self.source_rate_limited_frames.get(),
+            );
+            encoder.sample(
+                name,
+                &[("limit", &"gateway")],
+                self.gateway_rate_limited_frames.get(),
+            );
+
+            let name = "semtech_udp_server_parse_errors_suppressed_total";
+            encoder.header(
+                name,
+                "counter",
+                "Parse errors not reported because of the parse error rate limit",
+            );
+            encoder.sample(name, &[], self.parse_errors_suppressed.get());
+
+            let name = "semtech_udp_server_bytes_received_total";
+            encoder.header(name, "counter", "UDP payload bytes received");
+            encoder.sample(name, &[], self.bytes_received.get());
+
+            let name = "semtech_udp_server_acks_sent_total";
+            encoder.header(name, "counter", "PUSH_ACK and PULL_ACK frames sent");
+            encoder.sample(name, &[("type", &"push_ack")], self.push_acks_sent.get());
+            encoder.sample(name, &[("type", &"pull_ack")], self.pull_acks_sent.get());
+
+            let name = "semtech_udp_server_ack_send_errors_total";
+            encoder.header(
+                name,
+                "counter",
+                "PUSH_ACK and PULL_ACK frames which failed to send",
+            );
+            encoder.sample(name, &[], self.ack_send_errors.get());
+
+            let name = "semtech_udp_server_downlink_send_failures_total";
+            encoder.header(name, "counter", "PULL_RESP frames which failed to send");
+            encoder.sample(name, &[], self.downlink_send_failures.get());
+
+            let name = "semtech_udp_server_downlinks_pending";
+            encoder.header(name, "gauge", "Downlinks sent and awaiting their TX_ACK");
+            encoder.sample(name, &[], self.downlinks_pending.get());
+
+            let name = "semtech_udp_server_internal_queue_depth";
+            encoder.header(name, "gauge", "Frames and requests waiting to be processed");
+            encoder.sample(name, &[], snapshot.internal_queue_depth);
+
+            let name = "semtech_udp_server_event_queue_depth";
+            encoder.header(name, "gauge", "Events waiting to be received by the client");
+            encoder.sample(name, &[], snapshot.event_queue_depth);
+
+            let name = "semtech_udp_server_events_dropped_total";
+            encoder.header(name, "counter", "Events dropped by the overflow policy");
+            encoder.sample(name, &[], snapshot.events_dropped);
+
+            let name = "semtech_udp_server_gateways_connected";
+            encoder.header(name, "gauge", "Gateways which sent PULL_DATA recently");
+            encoder.sample(name, &[], snapshot.gateways_connected);
+
+            let name = "semtech_udp_server_rxpk_total";
+            encoder.header(name, "counter", "Received packets, by CRC status");
+            for (crc, counter) in [
+                ("ok", &self.rxpk_crc_ok),
+                ("fail", &self.rxpk_crc_fail),
+                ("none", &self.rxpk_no_crc),
+            ] {
+                encoder.sample(name, &[("crc", &crc)], counter.get());
+            }
+
+            let name = "semtech_udp_server_stat_total";
+            encoder.header(name, "counter", "Stat objects");
+            encoder.sample(name, &[], self.stat_objects.get());
+
+            let name = "semtech_udp_server_downlinks_sent_total";
+            encoder.header(name, "counter", "PULL_RESP frames sent");
+            encoder.sample(name, &[], self.downlinks_sent.get());
+
+            let name = "semtech_udp_server_ack_timeouts_total";
+            encoder.header(name, "counter", "Downlinks which expired without a TX_ACK");
+            encoder.sample(name, &[], self.ack_timeouts.get());
+
+            let name = "semtech_udp_server_tx_ack_result_total";
+            encoder.header(name, "counter", "TX_ACK results, by error");
+            for (result, counter) in TX_ACK_RESULTS.iter().zip(&self.tx_ack_results) {
+                encoder.sample(name, &[("result", result)], counter.get());
+            }
+
+            let buckets = self
+                .ack_latency_buckets
+                .each_ref()
+                .map(|counter| counter.get());
+            let bounds = ACK_LATENCY_BUCKETS_MS.map(|ms| ms as f64 / 1000.0);
+            encoder.histogram(
+                "semtech_udp_server_tx_ack_latency_seconds",
+                "Time between sending a downlink and receiving its TX_ACK",
+                &bounds,
+                &buckets,
+                self.ack_latency_sum_ms.get() as f64 / 1000.0,
+            );
+
+            render_gateways(&mut encoder, snapshot.gateways);
+            encoder.finish()
+        }
+    }
+
+    // gateways are few enough to label counters by MAC; a gateway's series end when it
+    // disconnects and restart from zero if it connects again
+    fn render_gateways(encoder: &mut TextEncoder, gateways: &HashMap<MacAddress, GatewayStats>) {
+        let counters: [GatewayCounter; 7] = [
+            ("push_data", "PUSH_DATA frames", |stats| stats.push_data),
+            ("pull_data", "PULL_DATA frames", |stats| stats.pull_data),
+            ("stat", "Stat objects", |stats| stats.stat),
+            ("downlinks_sent", "PULL_RESP frames sent", |stats| {
+                stats.downlinks_sent
+            }),
+            (
+                "parse_failures",
+                "Frames which could not be parsed",
+                |stats| stats.parse_failures,
+            ),
+            ("tx_ack", "TX_ACK frames matched to a downlink", |stats| {
+                stats.tx_ack.total()
+            }),
+            (
+                "ack_timeouts",
+                "Downlinks which expired without a TX_ACK",
+                |stats| stats.ack_timeouts,
+            ),
+        ];
+        for (suffix, help, value) in counters {
+            let name = format!("semtech_udp_server_gateway_{suffix}_total");
+            encoder.header(&name, "counter", help);
+            for (mac, stats) in gateways {
+                encoder.sample(&name, &[("gateway", mac)], value(stats));
+            }
+        }
+
+        let name = "semtech_udp_server_gateway_rxpk_total";
+        encoder.header(name, "counter", "Received packets, by CRC status");
+        for (mac, stats) in gateways {
+            for (crc, count) in [
+                ("ok", stats.rxpk_crc_ok),
+                ("fail", stats.rxpk_crc_fail),
+                ("none", stats.rxpk_no_crc),
+            ] {
+                encoder.sample(name, &[("gateway", mac), ("crc", &crc)], count);
+            }
+        }
+
+        let name = "semtech_udp_server_gateway_tx_ack_result_total";
+        encoder.header(name, "counter", "TX_ACK results, by error");
+        for (mac, stats) in gateways {
+            let acks = &stats.tx_ack;
+            let counts = [
+                acks.ok,
+                acks.too_late,
+                acks.too_early,
+                acks.collision_packet,
+                acks.collision_beacon,
+                acks.tx_freq,
+                acks.tx_power,
+                acks.adjusted_tx_power,
+                acks.gps_unlocked,
+                acks.send_lbt,
+                acks.send_fail,
+            ];
+            for (result, count) in TX_ACK_RESULTS.iter().zip(counts) {
+                encoder.sample(name, &[("gateway", mac), ("result", result)], count);
+            }
+        }
+    }
+}
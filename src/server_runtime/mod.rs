@@ -1,37 +1,104 @@
 use super::{
-    pull_resp, pull_resp::TxPk, tx_ack::Packet as TxAck, MacAddress, Packet, ParseError,
+    pull_resp, pull_resp::TxPk, push_ack, tx_ack::Packet as TxAck, MacAddress, Packet, ParseError,
     SerializablePacket, Up,
 };
+use crate::metrics::Counter;
 pub use crate::push_data::{RxPk, Stat};
-use std::sync::Arc;
-use std::time::SystemTime;
-use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use crate::sync::lock;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+use std::{future::poll_fn, io, task::Poll};
 use tokio::{
+    io::ReadBuf,
     net::{ToSocketAddrs, UdpSocket},
-    sync::{mpsc, oneshot},
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
     time::timeout,
 };
 
 mod error;
 pub use error::Error;
+
+mod config;
+pub use config::{MacConflictPolicy, OverflowPolicy, ServerConfig};
+
+mod stats;
+pub use stats::{AckLatency, GatewayStats, TxAckCounts, ACK_LATENCY_BUCKETS_MS};
+use stats::{StatsTable, STATS_RETENTION};
+
+mod metrics;
+use metrics::ServerMetrics;
+
+mod admission;
+use admission::UNKNOWN_GATEWAY_REPORT_INTERVAL;
+pub use admission::{Admission, AdmissionPolicy, AllowAll, Allowlist, InvalidCidr, IpCidr};
+
+mod event_queue;
+use event_queue::{EventReceiver, EventSender};
+
+#[cfg(feature = "lorawan")]
+pub mod router;
+
+mod pending;
+use pending::{PendingDownlinks, UnmatchedAck};
+
+mod conflict;
+use conflict::AddressHistory;
+
+mod rate_limit;
+pub use rate_limit::RateLimit;
+use rate_limit::RateLimiter;
+
+#[cfg(feature = "lorawan")]
+mod selection;
+#[cfg(feature = "lorawan")]
+use selection::UplinkHistory;
+#[cfg(feature = "lorawan")]
+pub use selection::{Candidate, DeviceKey, GatewaySelection};
+
+mod dedup;
+pub use dedup::{DedupConfig, DedupedPacket, UplinkCopy};
+use dedup::{Deduplicator, Insert};
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
-const DEFAULT_DISCONNECT_THRESHOLD: u64 = 60;
-const DEFAULT_CACHE_CHECK_FREQ: u64 = 60;
-const MAX_MESSAGE_SIZE: usize = 65535;
+// the TX_ACK for a downlink, or the reason it could not be sent
+type AckSender = oneshot::Sender<Result<TxAck>>;
 
 #[derive(Debug)]
 enum InternalEvent {
-    Downlink((pull_resp::Packet, MacAddress, oneshot::Sender<TxAck>)),
-    PacketBySocket((Packet, SocketAddr)),
-    Client((MacAddress, SocketAddr)),
+    Downlink((pull_resp::Packet, MacAddress, AckSender)),
+    // PULL_DATA from the gateway, with its protocol version and the socket it arrived on
+    Client((MacAddress, SocketAddr, u8, usize)),
+    UnknownGateway((MacAddress, SocketAddr)),
+    PushDataReceived(MacAddress),
     PacketReceived(RxPk, MacAddress),
     StatReceived(Stat, MacAddress),
-    UnableToParseUdpFrame(ParseError, Vec<u8>),
+    UnableToParseUdpFrame(ParseError, Vec<u8>, SocketAddr),
     AckReceived(TxAck),
     CheckCache,
     FailedSend((Box<pull_resp::Packet>, MacAddress)),
-    SuccessSend((u16, oneshot::Sender<TxAck>)),
+    SuccessSend((MacAddress, u16)),
+    DedupWindowClosed(u64),
+    AckExpired((MacAddress, u16, Instant)),
+    #[cfg(feature = "lorawan")]
+    SelectGateways((DeviceKey, GatewaySelection, oneshot::Sender<Vec<Candidate>>)),
+    ListGateways(oneshot::Sender<Vec<GatewayInfo>>),
+    GetGateway((MacAddress, oneshot::Sender<Option<GatewayInfo>>)),
+    EvictGateway((MacAddress, oneshot::Sender<Option<GatewayInfo>>)),
+}
+
+impl InternalEvent {
+    // fails the downlink carried by the event, if any, because the runtime is stopping
+    fn reject(self) {
+        if let InternalEvent::Downlink((_, _, ack_sender)) = self {
+            let _ = ack_sender.send(Err(Error::Shutdown));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -43,6 +110,20 @@ pub enum Event {
     UnableToParseUdpFrame(ParseError, Vec<u8>),
     NoClientWithMac(Box<pull_resp::Packet>, MacAddress),
     ClientDisconnected((MacAddress, SocketAddr)),
+    // only emitted when deduplication is enabled, instead of PacketReceived
+    DedupedPacketReceived(DedupedPacket),
+    LateDuplicate(RxPk, MacAddress),
+    // a downlink was sent but no TX_ACK arrived before the pending ACK expiry
+    AckTimeout((MacAddress, u16)),
+    // a TX_ACK whose token is awaited from another gateway, with that gateway's MAC
+    MismatchedTxAck(TxAck, MacAddress),
+    // a TX_ACK matching no pending downlink, with the time since its downlink was sent
+    // when the caller stopped waiting or the downlink expired recently
+    UnmatchedTxAck(TxAck, Option<Duration>),
+    // a frame from a MAC unknown to the admission policy, which was not ACKed
+    UnknownGateway((MacAddress, SocketAddr)),
+    // a MAC claimed from several addresses, first seen first; emitted when the conflict starts
+    MacConflict((MacAddress, Vec<SocketAddr>)),
 }
 
 // receives requests from clients
@@ -51,42 +132,91 @@ pub enum Event {
 #[allow(dead_code)]
 pub struct ClientTx {
     sender: mpsc::Sender<InternalEvent>,
+    ack_timeout: Option<Duration>,
+    gateways: watch::Receiver<GatewayAddrs>,
+    stats: Arc<Mutex<StatsTable>>,
+    metrics: Arc<ServerMetrics>,
+    #[cfg(feature = "metrics")]
+    event_queue: event_queue::EventQueueMonitor,
+    // never sent on: Internal sees the channel close once every ClientTx is dropped
+    _handle: mpsc::Sender<()>,
 }
 
 // sends packets to clients
 #[derive(Debug)]
 pub struct ClientRx {
-    receiver: mpsc::Receiver<Event>,
+    receiver: EventReceiver,
 }
 
 // receives and parses UDP packets
 struct UdpRx {
-    socket_receiver: Arc<UdpSocket>,
+    sockets: Vec<Arc<UdpSocket>>,
+    // socket polled first, rotated so that a busy socket cannot starve the others
+    next_socket: usize,
     internal_sender: mpsc::Sender<InternalEvent>,
+    cache_check_freq: Duration,
+    max_message_size: usize,
+    admission: Arc<dyn AdmissionPolicy>,
+    source_limiter: Option<RateLimiter<SocketAddr>>,
+    gateway_limiter: Option<RateLimiter<MacAddress>>,
+    parse_error_limiter: Option<RateLimiter<()>>,
+    unknown_gateway_limiter: RateLimiter<(MacAddress, SocketAddr)>,
+    metrics: Arc<ServerMetrics>,
 }
 
 // processes Internal Events and Transmit over UDP
 struct Internal {
     self_sender: mpsc::Sender<InternalEvent>,
     receiver: mpsc::Receiver<InternalEvent>,
-    client_tx_sender: mpsc::Sender<Event>,
+    client_tx_sender: EventSender,
+    client_tx_handles: mpsc::Receiver<()>,
     clients: HashMap<MacAddress, Client>,
-    downlink_senders: HashMap<u16, oneshot::Sender<TxAck>>,
-    socket_sender: Arc<UdpSocket>,
+    address_history: HashMap<MacAddress, AddressHistory>,
+    conflicted: HashSet<MacAddress>,
+    mac_conflict: MacConflictPolicy,
+    mac_conflict_window: Duration,
+    gateways_watch: watch::Sender<GatewayAddrs>,
+    downlink_senders: PendingDownlinks,
+    // shared with ClientTx, which reads it without a round trip through Internal
+    stats: Arc<Mutex<StatsTable>>,
+    sockets: Vec<Arc<UdpSocket>>,
     disconnect_threshold: Option<Duration>,
+    pending_ack_expiry: Duration,
+    max_message_size: usize,
+    dedup: Option<Deduplicator>,
+    #[cfg(feature = "lorawan")]
+    uplink_history: UplinkHistory,
+    last_nack: HashMap<MacAddress, Instant>,
+    metrics: Arc<ServerMetrics>,
+    shutdown_signal: triggered::Listener,
 }
 
 #[derive(Debug, Clone)]
 struct Client {
     addr: SocketAddr,
+    // index of the socket the gateway's PULL_DATA arrive on, which downlinks are sent through
+    socket: usize,
+    first_seen: SystemTime,
     last_seen: SystemTime,
+    last_pull_data: SystemTime,
+    // monotonic copy of last_pull_data, which the disconnect threshold is measured against
+    pulled_at: Instant,
+    last_push_data: Option<SystemTime>,
+    protocol_version: u8,
 }
 
 impl Client {
-    fn new(addr: SocketAddr) -> Self {
+    fn new(addr: SocketAddr, socket: usize, protocol_version: u8) -> Self {
+        let now = SystemTime::now();
         Client {
             addr,
-            last_seen: SystemTime::now(),
+            socket,
+            first_seen: now,
+            last_seen: now,
+            last_pull_data: now,
+            pulled_at: Instant::now(),
+            last_push_data: None,
+            protocol_version,
         }
     }
     fn addr(&self) -> &SocketAddr {
@@ -95,32 +225,95 @@ impl Client {
 
     fn update_addr(&mut self, new_addr: SocketAddr) {
         self.addr = new_addr;
-        self.seen();
+        self.pulled();
+    }
+
+    // the gateway is only reachable for downlinks while it keeps sending PULL_DATA
+    fn pulled(&mut self) {
+        self.last_seen = SystemTime::now();
+        self.last_pull_data = self.last_seen;
+        self.pulled_at = Instant::now();
     }
 
-    fn seen(&mut self) {
+    fn pushed(&mut self) {
         self.last_seen = SystemTime::now();
+        self.last_push_data = Some(self.last_seen);
     }
+
+    fn info(&self, mac: MacAddress) -> GatewayInfo {
+        GatewayInfo {
+            mac,
+            addr: self.addr,
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+            last_pull_data: self.last_pull_data,
+            last_push_data: self.last_push_data,
+            protocol_version: self.protocol_version,
+        }
+    }
+}
+
+// A gateway known to the runtime, ie: which has sent PULL_DATA within the disconnect threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayInfo {
+    pub mac: MacAddress,
+    pub addr: SocketAddr,
+    pub first_seen: SystemTime,
+    // latest PULL_DATA or PUSH_DATA
+    pub last_seen: SystemTime,
+    pub last_pull_data: SystemTime,
+    pub last_push_data: Option<SystemTime>,
+    pub protocol_version: u8,
 }
 
+// the address of every connected gateway, republished whenever a gateway connects,
+// changes address or is disconnected
+pub type GatewayAddrs = Arc<HashMap<MacAddress, SocketAddr>>;
+
 #[derive(Debug)]
 pub struct UdpRuntime {
     rx: ClientRx,
     tx: ClientTx,
+    handle: RuntimeHandle,
+}
+
+// Stops the runtime and reports why it stopped. Dropping the handle leaves the
+// runtime running until ClientRx and every ClientTx are dropped too.
+#[derive(Debug)]
+pub struct RuntimeHandle {
+    shutdown_trigger: triggered::Trigger,
+    task: JoinHandle<Result>,
+}
+
+impl RuntimeHandle {
+    // downlinks awaiting their TX_ACK fail with Error::Shutdown
+    pub fn shutdown(&self) {
+        self.shutdown_trigger.trigger()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    // resolves when the runtime stops: Ok after a shutdown, or the error which ended it
+    pub async fn join(self) -> Result {
+        self.task.await?
+    }
 }
-use rand::Rng;
 
 #[derive(Clone)]
 pub struct Downlink {
     mac: MacAddress,
     packet: Option<pull_resp::Packet>,
     sender: mpsc::Sender<InternalEvent>,
+    ack_timeout: Option<Duration>,
 }
 
 impl Downlink {
     pub fn set_packet(&mut self, txpk: TxPk) {
         self.packet = Some(pull_resp::Packet {
-            random_token: rand::thread_rng().gen(),
+            // allocated by the runtime when the downlink is sent
+            random_token: 0,
             data: pull_resp::Data::from_txpk(txpk),
         });
     }
@@ -138,26 +331,42 @@ impl Downlink {
                 .await?;
 
             // wait for the ACK for the protocol layer
-            receiver.await?.get_result().map_err(|e| e.into())
+            receiver.await??.get_result().map_err(|e| e.into())
         } else {
             Err(Error::DispatchWithNoSendPacket)
         }
     }
 
+    // without an explicit timeout, the ack_timeout of the ServerConfig applies
     pub async fn dispatch(self, timeout_duration: Option<Duration>) -> Result<Option<u32>> {
-        if let Some(duration) = timeout_duration {
-            timeout(duration, self.just_dispatch()).await?
-        } else {
-            self.just_dispatch().await
-        }
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("downlink", mac = %self.mac);
+        let dispatched = async move {
+            let result = if let Some(duration) = timeout_duration.or(self.ack_timeout) {
+                timeout(duration, self.just_dispatch())
+                    .await
+                    .unwrap_or_else(|elapsed| Err(elapsed.into()))
+            } else {
+                self.just_dispatch().await
+            };
+            debug!(?result, "downlink dispatched");
+            result
+        };
+        #[cfg(feature = "tracing")]
+        let dispatched = tracing::Instrument::instrument(dispatched, span);
+        dispatched.await
     }
 }
 
 impl ClientRx {
-    pub async fn recv(&mut self) -> Event {
-        // we unwrap here because the send channel is dropped only iff ClientRx is dropped
-        // ClientRx panics before it can get dropped (see UdpRuntime)
-        self.receiver.recv().await.unwrap()
+    // returns None once the runtime has stopped and all events have been received
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.receiver.recv().await
+    }
+
+    // events discarded by the ServerConfig's overflow policy, or because ClientRx was dropped
+    pub fn dropped_events(&self) -> u64 {
+        self.receiver.dropped_events()
     }
 }
 
@@ -172,9 +381,89 @@ impl ClientTx {
         prepared_send.dispatch(timeout).await
     }
 
+    // sends to the gateways which recently heard the device, in the order given by
+    // `selection`, until one accepts the downlink. The downlink is built for each gateway
+    // by `txpk`, from the `tmst` of the device's uplink on that gateway's clock, e.g.
+    // `Time::by_tmst(candidate.tmst.wrapping_add(RX1_DELAY))`. Returns the gateway used.
+    #[cfg(feature = "lorawan")]
+    pub async fn send_to_device<F>(
+        &mut self,
+        device: DeviceKey,
+        mut txpk: F,
+        selection: GatewaySelection,
+        timeout: Option<Duration>,
+    ) -> Result<(MacAddress, Option<u32>)>
+    where
+        F: FnMut(&Candidate) -> TxPk,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(InternalEvent::SelectGateways((device, selection, sender)))
+            .await?;
+
+        let mut result = Err(Error::NoGatewayForDevice);
+        for candidate in receiver.await? {
+            let mac = candidate.gateway_mac;
+            match self.send(txpk(&candidate), mac, timeout).await {
+                Ok(tmst) => return Ok((mac, tmst)),
+                Err(e) if e.should_try_next_gateway() => result = Err(e),
+                Err(e) => return Err(e),
+            }
+        }
+        result
+    }
+
+    pub async fn gateways(&self) -> Result<Vec<GatewayInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(InternalEvent::ListGateways(sender))
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub async fn gateway(&self, mac: MacAddress) -> Result<Option<GatewayInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(InternalEvent::GetGateway((mac, sender)))
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    // forgets the gateway as if it had disconnected; it is registered again by its next PULL_DATA
+    pub async fn evict_gateway(&self, mac: MacAddress) -> Result<Option<GatewayInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(InternalEvent::EvictGateway((mac, sender)))
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub fn watch_gateways(&self) -> watch::Receiver<GatewayAddrs> {
+        self.gateways.clone()
+    }
+
+    // the runtime's metrics in the Prometheus text format
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String {
+        let gateways = self.gateway_stats();
+        self.metrics.render(metrics::Snapshot {
+            internal_queue_depth: self.sender.max_capacity() - self.sender.capacity(),
+            event_queue_depth: self.event_queue.len(),
+            events_dropped: self.event_queue.dropped_events(),
+            gateways_connected: self.gateways.borrow().len(),
+            gateways: &gateways,
+        })
+    }
+
+    // counters of every connected gateway, and of the other MACs heard recently
+    pub fn gateway_stats(&self) -> HashMap<MacAddress, GatewayStats> {
+        lock(&self.stats).snapshot()
+    }
+
     pub fn prepare_downlink(&mut self, txpk: Option<TxPk>, mac: MacAddress) -> Downlink {
         let packet = txpk.map(|txpk| pull_resp::Packet {
-            random_token: rand::thread_rng().gen(),
+            // allocated by the runtime when the downlink is sent
+            random_token: 0,
             data: pull_resp::Data::from_txpk(txpk),
         });
 
@@ -182,6 +471,7 @@ impl ClientTx {
             mac,
             packet,
             sender: self.get_sender(),
+            ack_timeout: self.ack_timeout,
         }
     }
 
@@ -190,6 +480,20 @@ impl ClientTx {
     }
 }
 
+fn bind_socket(addr: SocketAddr, only_v6: bool) -> Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
 impl UdpRuntime {
     pub fn split(self) -> (ClientRx, ClientTx) {
         (self.rx, self.tx)
@@ -204,6 +508,47 @@ impl UdpRuntime {
         self.tx.send(txpk, mac, timeout).await
     }
 
+    #[cfg(feature = "lorawan")]
+    pub async fn send_to_device<F>(
+        &mut self,
+        device: DeviceKey,
+        txpk: F,
+        selection: GatewaySelection,
+        timeout: Option<Duration>,
+    ) -> Result<(MacAddress, Option<u32>)>
+    where
+        F: FnMut(&Candidate) -> TxPk,
+    {
+        self.tx
+            .send_to_device(device, txpk, selection, timeout)
+            .await
+    }
+
+    pub async fn gateways(&self) -> Result<Vec<GatewayInfo>> {
+        self.tx.gateways().await
+    }
+
+    pub async fn gateway(&self, mac: MacAddress) -> Result<Option<GatewayInfo>> {
+        self.tx.gateway(mac).await
+    }
+
+    pub async fn evict_gateway(&self, mac: MacAddress) -> Result<Option<GatewayInfo>> {
+        self.tx.evict_gateway(mac).await
+    }
+
+    pub fn watch_gateways(&self) -> watch::Receiver<GatewayAddrs> {
+        self.tx.watch_gateways()
+    }
+
+    pub fn gateway_stats(&self) -> HashMap<MacAddress, GatewayStats> {
+        self.tx.gateway_stats()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String {
+        self.tx.render_metrics()
+    }
+
     pub fn prepare_empty_downlink(&mut self, mac: MacAddress) -> Downlink {
         self.tx.prepare_downlink(None, mac)
     }
@@ -212,20 +557,92 @@ impl UdpRuntime {
         self.tx.prepare_downlink(Some(txpk), mac)
     }
 
-    pub async fn recv(&mut self) -> Event {
+    pub async fn recv(&mut self) -> Option<Event> {
         self.rx.recv().await
     }
 
+    pub fn shutdown(&self) {
+        self.handle.shutdown()
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.rx.dropped_events()
+    }
+
+    // like split, but also returns the handle supervising the runtime
+    pub fn split_with_handle(self) -> (ClientRx, ClientTx, RuntimeHandle) {
+        (self.rx, self.tx, self.handle)
+    }
+
     pub async fn new<A: ToSocketAddrs>(addr: A) -> Result<UdpRuntime> {
+        Self::new_with_config(addr, ServerConfig::default()).await
+    }
+
+    // identical copies of an uplink heard by several gateways are aggregated into
+    // a single Event::DedupedPacketReceived
+    pub async fn new_with_dedup<A: ToSocketAddrs>(
+        addr: A,
+        dedup: DedupConfig,
+    ) -> Result<UdpRuntime> {
+        Self::new_with_config(addr, ServerConfig::default().dedup(Some(dedup))).await
+    }
+
+    pub async fn new_with_config<A: ToSocketAddrs>(
+        addr: A,
+        config: ServerConfig,
+    ) -> Result<UdpRuntime> {
         let socket = UdpSocket::bind(&addr).await?;
-        let socket_receiver = Arc::new(socket);
-        let socket_sender = socket_receiver.clone();
+        Self::with_sockets(vec![socket], config)
+    }
+
+    // listens on every address, e.g. on IPv4 and IPv6 or on distinct up and down ports;
+    // downlinks are sent through the socket the gateway's PULL_DATA arrived on
+    pub async fn new_with_addrs(addrs: &[SocketAddr], config: ServerConfig) -> Result<UdpRuntime> {
+        // IPv6 sockets are dual-stack unless IPv4 addresses are listened on separately
+        let only_v6 = addrs.iter().any(SocketAddr::is_ipv4);
+        let sockets = addrs
+            .iter()
+            .map(|addr| bind_socket(*addr, only_v6))
+            .collect::<Result<Vec<_>>>()?;
+        Self::with_sockets(sockets, config)
+    }
+
+    fn with_sockets(sockets: Vec<UdpSocket>, config: ServerConfig) -> Result<UdpRuntime> {
+        config.validate()?;
+        if sockets.is_empty() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on").into(),
+            );
+        }
+        for socket in &sockets {
+            let socket_ref = socket2::SockRef::from(socket);
+            if let Some(size) = config.socket_recv_buffer_size {
+                socket_ref.set_recv_buffer_size(size)?;
+            }
+            if let Some(size) = config.socket_send_buffer_size {
+                socket_ref.set_send_buffer_size(size)?;
+            }
+        }
+        let sockets: Vec<_> = sockets.into_iter().map(Arc::new).collect();
 
-        let (udp_tx_sender, udp_tx_receiver) = mpsc::channel(100);
-        let (client_tx_sender, client_tx_receiver) = mpsc::channel(100);
+        let (udp_tx_sender, udp_tx_receiver) = mpsc::channel(config.internal_queue_size);
+        let (client_tx_sender, client_tx_receiver) =
+            event_queue::channel(config.event_queue_size, config.event_overflow);
+
+        let (gateways_watch, gateways) = watch::channel(GatewayAddrs::default());
+        let metrics = Arc::new(ServerMetrics::default());
+        let stats = Arc::new(Mutex::new(StatsTable::default()));
+        let (client_tx_handle, client_tx_handles) = mpsc::channel(1);
 
         let client_tx = ClientTx {
             sender: udp_tx_sender.clone(),
+            ack_timeout: config.ack_timeout,
+            gateways,
+            stats: stats.clone(),
+            metrics: metrics.clone(),
+            #[cfg(feature = "metrics")]
+            event_queue: client_tx_sender.monitor(),
+            _handle: client_tx_handle,
         };
 
         let client_rx = ClientRx {
@@ -233,267 +650,1191 @@ impl UdpRuntime {
         };
 
         let udp_rx = UdpRx {
-            socket_receiver,
+            sockets: sockets.clone(),
+            next_socket: 0,
             internal_sender: udp_tx_sender.clone(),
+            cache_check_freq: config.cache_check_freq,
+            max_message_size: config.max_message_size,
+            admission: config.admission.unwrap_or_else(|| Arc::new(AllowAll)),
+            source_limiter: config
+                .source_rate_limit
+                .map(|limit| RateLimiter::new(limit, Instant::now())),
+            gateway_limiter: config
+                .gateway_rate_limit
+                .map(|limit| RateLimiter::new(limit, Instant::now())),
+            parse_error_limiter: config
+                .parse_error_rate_limit
+                .map(|limit| RateLimiter::new(limit, Instant::now())),
+            unknown_gateway_limiter: RateLimiter::new(
+                RateLimit::new(1.0 / UNKNOWN_GATEWAY_REPORT_INTERVAL.as_secs_f64(), 1),
+                Instant::now(),
+            ),
+            metrics: metrics.clone(),
         };
 
+        let (shutdown_trigger, shutdown_signal) = triggered::trigger();
         let udp_tx = Internal {
             self_sender: udp_tx_sender,
             receiver: udp_tx_receiver,
             client_tx_sender,
+            client_tx_handles,
             clients: HashMap::new(),
-            downlink_senders: HashMap::new(),
-            socket_sender,
-            disconnect_threshold: Some(Duration::from_secs(DEFAULT_DISCONNECT_THRESHOLD)),
+            address_history: HashMap::new(),
+            conflicted: HashSet::new(),
+            mac_conflict: config.mac_conflict,
+            mac_conflict_window: config.mac_conflict_window,
+            gateways_watch,
+            downlink_senders: PendingDownlinks::new(config.pending_ack_expiry),
+            stats,
+            sockets,
+            disconnect_threshold: config.disconnect_threshold,
+            pending_ack_expiry: config.pending_ack_expiry,
+            max_message_size: config.max_message_size,
+            dedup: config.dedup.map(Deduplicator::new),
+            #[cfg(feature = "lorawan")]
+            uplink_history: UplinkHistory::new(config.uplink_history),
+            last_nack: HashMap::new(),
+            metrics,
+            shutdown_signal,
         };
 
-        // udp_rx reads from the UDP port
-        // and sends packets to relevant parties
-        tokio::spawn(async move {
-            if let Err(e) = udp_rx.run().await {
-                // we panic here because the ony error case here
-                // if we lost the local socket somehow
-                panic!("UdpRx threw error: {e:?}")
-            }
-        });
-
-        // udp_tx writes to the UDP port and maintains
-        // gateway to IP map
-        tokio::spawn(async move {
-            if let Err(e) = udp_tx.run().await {
-                // we panic here because the ony error case here
-                // if we lost the local socket somehow
-                panic!("UdpTx threw error: {e:?}")
+        // udp_rx reads from the UDP port and sends packets to relevant parties
+        // udp_tx writes to the UDP port and maintains gateway to IP map
+        // whichever stops first stops the other, closing the socket
+        let task = tokio::spawn(async move {
+            let result = tokio::select!(
+                resp = udp_rx.run() => resp,
+                resp = udp_tx.run() => resp,
+            );
+            if let Err(_error) = &result {
+                error!(error = %_error, "server runtime stopped");
             }
+            result
         });
 
         Ok(UdpRuntime {
             rx: client_rx,
             tx: client_tx,
+            handle: RuntimeHandle {
+                shutdown_trigger,
+                task,
+            },
         })
     }
 }
 
 impl UdpRx {
-    pub async fn run(self) -> Result {
+    // ACKs are sent here rather than by Internal so that gateways are answered
+    // even while events wait on a slow client
+    async fn send_ack(
+        &self,
+        packet: Packet,
+        addr: SocketAddr,
+        socket: usize,
+        sent: &Counter,
+    ) -> Result {
+        let mut buf = [0u8; 16];
+        let n = packet.serialize(&mut buf)? as usize;
+        // this will be an error only if we have somehow lost UDP connection
+        // between receiving a packet and sending the ACK
+        match self.sockets[socket].send_to(&buf[..n], &addr).await {
+            Ok(_) => {
+                sent.inc();
+                debug!(%addr, ?packet, "ack sent");
+            }
+            Err(_error) => {
+                self.metrics.ack_send_errors.inc();
+                warn!(%addr, ?packet, error = %_error, "failed to send ack");
+            }
+        }
+        Ok(())
+    }
+
+    // frames are ACKed through the socket they arrived on
+    async fn handle_uplink(
+        &mut self,
+        packet: Up,
+        src: SocketAddr,
+        socket: usize,
+        protocol_version: u8,
+    ) -> Result {
+        debug!(identifier = %packet.identifier(), "frame received");
+        match self.admission.admit(packet.gateway_mac(), src) {
+            Admission::Accept => (),
+            admission => {
+                self.metrics.rejected_frames.inc();
+                warn!(?admission, "frame rejected by admission policy");
+                if admission == Admission::Unknown
+                    && self
+                        .unknown_gateway_limiter
+                        .check((packet.gateway_mac(), src), Instant::now())
+                {
+                    self.internal_sender
+                        .send(InternalEvent::UnknownGateway((packet.gateway_mac(), src)))
+                        .await?;
+                }
+                return Ok(());
+            }
+        }
+        if let Some(limiter) = &mut self.gateway_limiter {
+            if !limiter.check(packet.gateway_mac(), Instant::now()) {
+                self.metrics.gateway_rate_limited_frames.inc();
+                debug!("frame dropped by the gateway rate limit");
+                return Ok(());
+            }
+        }
+        match packet {
+            Up::PullData(pull_data) => {
+                self.metrics.pull_data_frames.inc();
+                let mac = pull_data.gateway_mac;
+                let ack_packet = pull_data.into_ack();
+                self.send_ack(ack_packet.into(), src, socket, &self.metrics.pull_acks_sent)
+                    .await?;
+
+                // send (mac, addr) to update map owned by UdpRuntimeTx
+                let client = (mac, src, protocol_version, socket);
+                self.internal_sender
+                    .send(InternalEvent::Client(client))
+                    .await?;
+            }
+            Up::TxAck(txack) => {
+                self.metrics.tx_ack_frames.inc();
+                self.internal_sender
+                    .send(InternalEvent::AckReceived(txack))
+                    .await?;
+            }
+            Up::PushData(push_data) => {
+                self.metrics.push_data_frames.inc();
+                let ack_packet = push_ack::Packet {
+                    random_token: push_data.random_token,
+                };
+                self.send_ack(ack_packet.into(), src, socket, &self.metrics.push_acks_sent)
+                    .await?;
+                self.internal_sender
+                    .send(InternalEvent::PushDataReceived(push_data.gateway_mac))
+                    .await?;
+
+                // Send all received packets as RxPk Events
+                if let Some(rxpk) = &push_data.data.rxpk {
+                    for packet in rxpk {
+                        self.internal_sender
+                            .send(InternalEvent::PacketReceived(
+                                packet.clone(),
+                                push_data.gateway_mac,
+                            ))
+                            .await?;
+                    }
+                }
+
+                if let Some(stat) = &push_data.data.stat {
+                    self.internal_sender
+                        .send(InternalEvent::StatReceived(
+                            stat.clone(),
+                            push_data.gateway_mac,
+                        ))
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // receives a datagram from whichever socket has one, with the index of that socket
+    async fn recv_from_any(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+        let sockets = &self.sockets;
+        let start = self.next_socket;
+        let received = poll_fn(|cx| {
+            for offset in 0..sockets.len() {
+                let index = (start + offset) % sockets.len();
+                let mut read_buf = ReadBuf::new(buf);
+                match sockets[index].poll_recv_from(cx, &mut read_buf) {
+                    Poll::Ready(Ok(src)) => {
+                        return Poll::Ready(Ok((read_buf.filled().len(), src, index)))
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => (),
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        self.next_socket = (start + 1) % self.sockets.len();
+        received
+    }
+
+    pub async fn run(mut self) -> Result {
         let cache_sender = self.internal_sender.clone();
-        let cache_sender = tokio::spawn(async move {
+        let cache_check_freq = self.cache_check_freq;
+        let cache_sender = async move {
             loop {
                 cache_sender.send(InternalEvent::CheckCache).await?;
-                tokio::time::sleep(Duration::from_secs(DEFAULT_CACHE_CHECK_FREQ)).await;
+                tokio::time::sleep(cache_check_freq).await;
             }
-        });
+        };
 
-        let socket_handler = tokio::spawn(async move {
-            let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let socket_handler = async move {
+            let mut buf = vec![0u8; self.max_message_size];
             loop {
-                match self.socket_receiver.recv_from(&mut buf).await {
+                match self.recv_from_any(&mut buf).await {
                     Err(e) => return Err(e.into()),
-                    Ok((n, src)) => {
+                    Ok((n, src, socket)) => {
+                        self.metrics.bytes_received.add(n as u64);
+                        if let Some(limiter) = &mut self.source_limiter {
+                            if !limiter.check(src, Instant::now()) {
+                                self.metrics.source_rate_limited_frames.inc();
+                                debug!(addr = %src, "frame dropped by the source rate limit");
+                                continue;
+                            }
+                        }
                         let packet = match Packet::parse_uplink(&buf[0..n]) {
                             Ok(packet) => Some(packet),
+                            Err(_)
+                                if self
+                                    .parse_error_limiter
+                                    .as_mut()
+                                    .is_some_and(|limiter| !limiter.check((), Instant::now())) =>
+                            {
+                                self.metrics.invalid_frames.inc();
+                                self.metrics.parse_errors_suppressed.inc();
+                                None
+                            }
                             Err(e) => {
+                                self.metrics.invalid_frames.inc();
+                                warn!(addr = %src, error = %e, "unable to parse UDP frame");
                                 let mut vec = Vec::new();
                                 vec.extend_from_slice(&buf[0..n]);
                                 self.internal_sender
-                                    .send(InternalEvent::UnableToParseUdpFrame(e, vec))
+                                    .send(InternalEvent::UnableToParseUdpFrame(e, vec, src))
                                     .await?;
                                 None
                             }
                         };
                         if let Some(packet) = packet {
-                            match packet {
-                                Up::PullData(pull_data) => {
-                                    let mac = pull_data.gateway_mac;
-                                    // first send (mac, addr) to update map owned by UdpRuntimeTx
-                                    let client = (mac, src);
-                                    self.internal_sender
-                                        .send(InternalEvent::Client(client))
-                                        .await?;
-
-                                    // send the ack_packet
-                                    let ack_packet = pull_data.into_ack();
-                                    self.internal_sender
-                                        .send(InternalEvent::PacketBySocket((
-                                            ack_packet.into(),
-                                            src,
-                                        )))
-                                        .await?
-                                }
-                                Up::TxAck(txack) => {
-                                    self.internal_sender
-                                        .send(InternalEvent::AckReceived(txack))
-                                        .await?;
-                                }
-                                Up::PushData(push_data) => {
-                                    // Send all received packets as RxPk Events
-                                    if let Some(rxpk) = &push_data.data.rxpk {
-                                        for packet in rxpk {
-                                            self.internal_sender
-                                                .send(InternalEvent::PacketReceived(
-                                                    packet.clone(),
-                                                    push_data.gateway_mac,
-                                                ))
-                                                .await?;
-                                        }
-                                    }
-
-                                    if let Some(stat) = &push_data.data.stat {
-                                        self.internal_sender
-                                            .send(InternalEvent::StatReceived(
-                                                stat.clone(),
-                                                push_data.gateway_mac,
-                                            ))
-                                            .await?;
-                                    }
-
-                                    let socket_addr = src;
-                                    // send the ack_packet
-                                    let ack_packet = push_data.into_ack();
-                                    self.internal_sender
-                                        .send(InternalEvent::PacketBySocket((
-                                            ack_packet.into(),
-                                            socket_addr,
-                                        )))
-                                        .await?;
-                                }
-                            }
+                            #[cfg(feature = "tracing")]
+                            let span = tracing::debug_span!(
+                                "gateway",
+                                mac = %packet.gateway_mac(),
+                                addr = %src
+                            );
+                            // the parser only accepts frames starting with the version
+                            let handled = self.handle_uplink(packet, src, socket, buf[0]);
+                            #[cfg(feature = "tracing")]
+                            let handled = tracing::Instrument::instrument(handled, span);
+                            handled.await?;
                         }
                     }
                 }
             }
-        });
+        };
 
         tokio::select!(
-            resp = cache_sender => resp?,
-            resp = socket_handler => resp?,
+            resp = cache_sender => resp,
+            resp = socket_handler => resp,
         )
     }
 }
 
+// resolves once nothing can use the runtime any more: ClientRx and every ClientTx are dropped
+async fn abandoned(client_tx_sender: &EventSender, client_tx_handles: &mut mpsc::Receiver<()>) {
+    client_tx_sender.closed().await;
+    while client_tx_handles.recv().await.is_some() {}
+}
+
 impl Internal {
+    fn publish_gateways(&self) {
+        self.gateways_watch.send_replace(Arc::new(
+            self.clients
+                .iter()
+                .map(|(mac, client)| (*mac, *client.addr()))
+                .collect(),
+        ));
+    }
+
+    // PULL_DATA binds the MAC to the address downlinks are sent to, unless it is in conflict
+    async fn pull_data(
+        &mut self,
+        mac: MacAddress,
+        addr: SocketAddr,
+        protocol_version: u8,
+        socket: usize,
+    ) {
+        lock(&self.stats).update(mac).pull_data += 1;
+        let now = Instant::now();
+        let competing = self
+            .address_history
+            .entry(mac)
+            .or_insert_with(|| AddressHistory::new(addr, now))
+            .observe(addr, now, self.mac_conflict_window);
+        let Some(competing) = competing else {
+            self.conflicted.remove(&mac);
+            self.bind(mac, addr, protocol_version, socket, true).await;
+            return;
+        };
+        if self.conflicted.insert(mac) {
+            warn!(%mac, addrs = ?competing, "gateway MAC claimed from several addresses");
+            self.emit(Event::MacConflict((mac, competing.clone())))
+                .await;
+        }
+        // the conflict was reported, so address changes are not
+        match self.mac_conflict {
+            MacConflictPolicy::LatestWins => {
+                self.bind(mac, addr, protocol_version, socket, false).await
+            }
+            MacConflictPolicy::FirstWins => {
+                if addr == competing[0] {
+                    self.bind(mac, addr, protocol_version, socket, false).await
+                }
+            }
+            MacConflictPolicy::Quarantine => {
+                if let Some(client) = self.clients.remove(&mac) {
+                    info!(%mac, "gateway quarantined");
+                    self.forget(&mac);
+                    self.publish_gateways();
+                    self.emit(Event::ClientDisconnected((mac, *client.addr())))
+                        .await;
+                }
+            }
+        }
+    }
+
+    async fn bind(
+        &mut self,
+        mac: MacAddress,
+        addr: SocketAddr,
+        protocol_version: u8,
+        socket: usize,
+        notify: bool,
+    ) {
+        // tell user if same MAC has new IP
+        if let Some(client) = self.clients.get_mut(&mac) {
+            client.protocol_version = protocol_version;
+            client.socket = socket;
+            if *client.addr() != addr {
+                client.update_addr(addr);
+                info!(%mac, %addr, "gateway address updated");
+                self.publish_gateways();
+                if notify {
+                    self.emit(Event::UpdateClient((mac, addr))).await;
+                }
+            } else {
+                // refresh the seen
+                client.pulled();
+            }
+        }
+        // simply insert if no entry exists
+        else {
+            self.clients
+                .insert(mac, Client::new(addr, socket, protocol_version));
+            info!(%mac, %addr, protocol_version, "gateway connected");
+            self.publish_gateways();
+            self.emit(Event::NewClient((mac, addr))).await;
+        }
+    }
+
+    // drops what is kept about a gateway once it is disconnected
+    fn forget(&mut self, mac: &MacAddress) {
+        lock(&self.stats).remove(mac);
+        self.last_nack.remove(mac);
+    }
+
+    // delivers an event to the client, applying the configured overflow policy; an event
+    // still waiting for room when the runtime shuts down is dropped
+    async fn emit(&self, event: Event) {
+        tokio::select!(
+            _ = self.client_tx_sender.send(event) => (),
+            _ = self.shutdown_signal.clone() => debug!("event dropped on shutdown"),
+        )
+    }
+
+    // fails every downlink still waiting on the runtime
+    fn drain(&mut self) {
+        info!(
+            pending = self.downlink_senders.len(),
+            "server runtime shutting down"
+        );
+        self.receiver.close();
+        while let Ok(event) = self.receiver.try_recv() {
+            event.reject();
+        }
+        for ack_sender in self.downlink_senders.drain() {
+            let _ = ack_sender.send(Err(Error::Shutdown));
+        }
+    }
+
     pub async fn run(mut self) -> Result {
-        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let mut buf = vec![0u8; self.max_message_size];
         loop {
-            let msg = self.receiver.recv().await;
+            let msg = tokio::select!(
+                biased;
+                _ = self.shutdown_signal.clone() => {
+                    self.drain();
+                    return Ok(());
+                }
+                _ = abandoned(&self.client_tx_sender, &mut self.client_tx_handles) => {
+                    info!("ClientRx and every ClientTx dropped");
+                    self.drain();
+                    return Ok(());
+                }
+                msg = self.receiver.recv() => msg,
+            );
             if let Some(msg) = msg {
                 match msg {
                     InternalEvent::CheckCache => {
-                        let now = SystemTime::now();
+                        let now = Instant::now();
                         if let Some(disconnect_threshold) = self.disconnect_threshold {
                             for (mac, client) in self.clients.clone().into_iter() {
-                                let time_since_last_seen = now
-                                    .duration_since(client.last_seen)
-                                    .map_err(|_| Error::LastSeen {
-                                        last_seen: client.last_seen,
-                                        now,
-                                    })?;
-
-                                if time_since_last_seen > disconnect_threshold {
+                                if now.duration_since(client.pulled_at) > disconnect_threshold {
                                     // Client not connected
-                                    self.client_tx_sender
-                                        .send(Event::ClientDisconnected((mac, *client.addr())))
-                                        .await?;
+                                    info!(%mac, addr = %client.addr(), "gateway disconnected");
+                                    self.emit(Event::ClientDisconnected((mac, *client.addr())))
+                                        .await;
                                     self.clients.remove(&mac);
+                                    self.forget(&mac);
+                                    self.publish_gateways();
                                 }
                             }
                         }
+                        if let Some(dedup) = &mut self.dedup {
+                            dedup.expire(now);
+                        }
+                        #[cfg(feature = "lorawan")]
+                        self.uplink_history.expire(now);
+                        self.downlink_senders.forget_expired(now);
+                        // gateways which never sent a PULL_DATA are not disconnected
+                        let clients = &self.clients;
+                        lock(&self.stats).expire(
+                            now,
+                            self.disconnect_threshold.unwrap_or(STATS_RETENTION),
+                            |mac| clients.contains_key(mac),
+                        );
+                        let window = self.mac_conflict_window;
+                        self.address_history
+                            .retain(|_, history| !history.expire(now, window));
+                        let address_history = &self.address_history;
+                        self.conflicted
+                            .retain(|mac| address_history.contains_key(mac));
                     }
-                    InternalEvent::UnableToParseUdpFrame(error, frame) => {
-                        self.client_tx_sender
-                            .send(Event::UnableToParseUdpFrame(error, frame))
-                            .await?;
+                    InternalEvent::UnableToParseUdpFrame(error, frame, src) => {
+                        // PUSH_DATA usually come from another port than PULL_DATA, so a frame is
+                        // attributed by source IP, and only when a single gateway uses that IP
+                        let mut senders = self
+                            .clients
+                            .iter()
+                            .filter(|(_, client)| client.addr().ip() == src.ip())
+                            .map(|(mac, _)| *mac);
+                        if let (Some(mac), None) = (senders.next(), senders.next()) {
+                            lock(&self.stats).update(mac).parse_failures += 1;
+                        }
+                        self.emit(Event::UnableToParseUdpFrame(error, frame)).await;
                     }
                     InternalEvent::PacketReceived(rxpk, mac) => {
-                        self.client_tx_sender
-                            .send(Event::PacketReceived(rxpk, mac))
-                            .await?;
+                        lock(&self.stats).update(mac).rxpk(&rxpk);
+                        self.metrics.rxpk(&rxpk);
+                        #[cfg(feature = "lorawan")]
+                        self.uplink_history.record(&rxpk, mac, Instant::now());
+                        if let Some(dedup) = &mut self.dedup {
+                            match dedup.insert(rxpk, mac, Instant::now()) {
+                                Insert::First(key) => {
+                                    let window = dedup.window();
+                                    let self_sender = self.self_sender.clone();
+                                    tokio::spawn(async move {
+                                        tokio::time::sleep(window).await;
+                                        let _ = self_sender
+                                            .send(InternalEvent::DedupWindowClosed(key))
+                                            .await;
+                                    });
+                                }
+                                Insert::Duplicate => (),
+                                Insert::Late(rxpk, mac) => {
+                                    self.emit(Event::LateDuplicate(rxpk, mac)).await;
+                                }
+                            }
+                        } else {
+                            self.emit(Event::PacketReceived(rxpk, mac)).await;
+                        }
+                    }
+                    InternalEvent::DedupWindowClosed(key) => {
+                        if let Some(packet) = self
+                            .dedup
+                            .as_mut()
+                            .and_then(|dedup| dedup.close(key, Instant::now()))
+                        {
+                            self.emit(Event::DedupedPacketReceived(packet)).await;
+                        }
                     }
                     InternalEvent::StatReceived(stat, mac) => {
-                        self.client_tx_sender
-                            .send(Event::StatReceived(stat, mac))
-                            .await?;
+                        lock(&self.stats).update(mac).stat += 1;
+                        self.metrics.stat_objects.inc();
+                        self.emit(Event::StatReceived(stat, mac)).await;
                     }
-                    InternalEvent::Downlink((packet, mac, ack_sender)) => {
+                    InternalEvent::Downlink((mut packet, mac, ack_sender)) => {
                         if let Some(client) = self.clients.get(&mac) {
+                            // the downlink awaits its TX_ACK from the moment it is sent
+                            let sent = Instant::now();
+                            packet.random_token =
+                                self.downlink_senders.insert(mac, ack_sender, sent);
+                            // a PULL_RESP which cannot be serialized fails this downlink only
+                            let n = match packet.serialize(&mut buf) {
+                                Ok(n) => n as usize,
+                                Err(error) => {
+                                    warn!(%mac, %error, "failed to serialize downlink");
+                                    if let Some((ack_sender, _)) =
+                                        self.downlink_senders.remove(mac, packet.random_token)
+                                    {
+                                        let _ = ack_sender.send(Err(error.into()));
+                                    }
+                                    continue;
+                                }
+                            };
+                            self.metrics
+                                .downlinks_pending
+                                .set(self.downlink_senders.len() as i64);
+                            let expiry = self.pending_ack_expiry;
+                            let self_sender = self.self_sender.clone();
+                            let random_token = packet.random_token;
+                            tokio::spawn(async move {
+                                tokio::time::sleep(expiry).await;
+                                let _ = self_sender
+                                    .send(InternalEvent::AckExpired((mac, random_token, sent)))
+                                    .await;
+                            });
+
                             // we spawn off here because one slow client can slow down all of the
                             // event processing
-                            let n = packet.serialize(&mut buf)? as usize;
                             let buf = Vec::from(&buf[..n]);
-                            let socket_sender = self.socket_sender.clone();
+                            let socket_sender = self.sockets[client.socket].clone();
                             let client_addr = *client.addr();
                             let self_sender = self.self_sender.clone();
-                            tokio::spawn(async move {
-                                match socket_sender.send_to(&buf, client_addr).await {
-                                    Err(_) => {
-                                        self_sender
-                                            .send(InternalEvent::FailedSend((packet.into(), mac)))
-                                            .await
-                                            .unwrap();
-                                    }
-                                    Ok(_) => {
-                                        self_sender
-                                            .send(InternalEvent::SuccessSend((
-                                                packet.random_token,
-                                                ack_sender,
-                                            )))
-                                            .await
-                                            .unwrap();
+                            #[cfg(feature = "tracing")]
+                            let span = tracing::debug_span!(
+                                "downlink",
+                                %mac,
+                                token = packet.random_token,
+                                addr = %client_addr
+                            );
+                            let send = async move {
+                                let event = match socket_sender.send_to(&buf, client_addr).await {
+                                    Err(_error) => {
+                                        warn!(error = %_error, "failed to send downlink");
+                                        InternalEvent::FailedSend((packet.into(), mac))
                                     }
-                                }
-                            });
+                                    Ok(_) => InternalEvent::SuccessSend((mac, packet.random_token)),
+                                };
+                                // if the runtime stopped meanwhile, it has failed the downlink
+                                let _ = self_sender.send(event).await;
+                            };
+                            #[cfg(feature = "tracing")]
+                            let send = tracing::Instrument::instrument(send, span);
+                            tokio::spawn(send);
                         } else {
-                            self.client_tx_sender
-                                .send(Event::NoClientWithMac(packet.into(), mac))
-                                .await?;
+                            warn!(%mac, token = packet.random_token, "no gateway for downlink");
+                            let _ = ack_sender.send(Err(Error::UnknownMac));
+                            self.emit(Event::NoClientWithMac(packet.into(), mac)).await;
                         }
                     }
                     InternalEvent::AckReceived(txack) => {
-                        if let Some(sender) = self.downlink_senders.remove(&txack.random_token) {
-                            // we may have received an ACK on a transmit that timed out already
-                            // therefore, this send may fail.
-                            let _ = sender.send(txack);
+                        let mac = txack.gateway_mac;
+                        if let Some((sender, sent)) =
+                            self.downlink_senders.remove(mac, txack.random_token)
+                        {
+                            self.metrics
+                                .downlinks_pending
+                                .set(self.downlink_senders.len() as i64);
+                            let result = txack.get_result();
+                            debug!(
+                                %mac,
+                                token = txack.random_token,
+                                ?result,
+                                latency_ms = sent.elapsed().as_millis() as u64,
+                                "tx_ack matched"
+                            );
+                            if result.is_err() {
+                                self.last_nack.insert(mac, Instant::now());
+                            }
+                            lock(&self.stats)
+                                .update(mac)
+                                .tx_ack(&result, sent.elapsed());
+                            self.metrics.tx_ack(&result, sent.elapsed());
+                            // the caller may have timed out already
+                            if let Err(Ok(txack)) = sender.send(Ok(txack)) {
+                                debug!(%mac, token = txack.random_token, "tx_ack after timeout");
+                                self.emit(Event::UnmatchedTxAck(txack, Some(sent.elapsed())))
+                                    .await;
+                            }
+                        } else {
+                            match self.downlink_senders.unmatched(mac, txack.random_token) {
+                                UnmatchedAck::Mismatched(expected) => {
+                                    warn!(
+                                        %mac,
+                                        %expected,
+                                        token = txack.random_token,
+                                        "tx_ack from a gateway the downlink was not sent to"
+                                    );
+                                    self.emit(Event::MismatchedTxAck(txack, expected)).await;
+                                }
+                                UnmatchedAck::Unmatched(sent) => {
+                                    let late = sent.map(|sent| sent.elapsed());
+                                    debug!(
+                                        %mac,
+                                        token = txack.random_token,
+                                        late_ms = late.map(|late| late.as_millis() as u64),
+                                        "unmatched tx_ack"
+                                    );
+                                    self.emit(Event::UnmatchedTxAck(txack, late)).await;
+                                }
+                            }
                         }
                     }
-                    InternalEvent::PacketBySocket((packet, addr)) => {
-                        let n = packet.serialize(&mut buf)? as usize;
-                        // only ACKs are sent via PacketBySocket
-                        // so this will be an error only if we have somehow lost UDP connection
-                        // between receiving a packet and sending the ACK
-                        let _ = self.socket_sender.send_to(&buf[..n], &addr).await;
+                    #[cfg(feature = "lorawan")]
+                    InternalEvent::SelectGateways((device, selection, sender)) => {
+                        let candidates = self.uplink_history.candidates(
+                            &device,
+                            selection,
+                            Instant::now(),
+                            |mac| self.downlink_senders.count(mac),
+                            |mac| self.last_nack.get(mac).copied(),
+                        );
+                        let _ = sender.send(candidates);
+                    }
+                    InternalEvent::UnknownGateway((mac, addr)) => {
+                        self.emit(Event::UnknownGateway((mac, addr))).await;
+                    }
+                    InternalEvent::Client((mac, addr, protocol_version, socket)) => {
+                        self.pull_data(mac, addr, protocol_version, socket).await;
                     }
-                    InternalEvent::Client((mac, addr)) => {
-                        // tell user if same MAC has new IP
+                    InternalEvent::PushDataReceived(mac) => {
+                        lock(&self.stats).update(mac).push_data += 1;
                         if let Some(client) = self.clients.get_mut(&mac) {
-                            if *client.addr() != addr {
-                                client.update_addr(addr);
-                                self.client_tx_sender
-                                    .send(Event::UpdateClient((mac, addr)))
-                                    .await?;
-                            } else {
-                                // refresh the seen
-                                client.seen();
-                            }
+                            client.pushed();
                         }
-                        // simply insert if no entry exists
-                        else {
-                            self.clients.insert(mac, Client::new(addr));
-                            self.client_tx_sender
-                                .send(Event::NewClient((mac, addr)))
-                                .await?;
+                    }
+                    InternalEvent::ListGateways(sender) => {
+                        let _ = sender.send(
+                            self.clients
+                                .iter()
+                                .map(|(mac, client)| client.info(*mac))
+                                .collect(),
+                        );
+                    }
+                    InternalEvent::GetGateway((mac, sender)) => {
+                        let _ = sender.send(self.clients.get(&mac).map(|client| client.info(mac)));
+                    }
+                    InternalEvent::EvictGateway((mac, sender)) => {
+                        let evicted = self.clients.remove(&mac);
+                        if let Some(client) = &evicted {
+                            info!(%mac, addr = %client.addr(), "gateway evicted");
+                            self.forget(&mac);
+                            self.publish_gateways();
+                            self.emit(Event::ClientDisconnected((mac, *client.addr())))
+                                .await;
                         }
+                        let _ = sender.send(evicted.map(|client| client.info(mac)));
                     }
-                    InternalEvent::SuccessSend((random_token, ack_sender)) => {
-                        self.downlink_senders.insert(random_token, ack_sender);
+                    InternalEvent::SuccessSend((mac, _random_token)) => {
+                        lock(&self.stats).update(mac).downlinks_sent += 1;
+                        self.metrics.downlinks_sent.inc();
+                        debug!(%mac, token = _random_token, "downlink sent");
+                    }
+                    InternalEvent::AckExpired((mac, random_token, sent)) => {
+                        // the downlink may have been acked and its token reused since
+                        if let Some(ack_sender) =
+                            self.downlink_senders
+                                .expire(mac, random_token, sent, Instant::now())
+                        {
+                            warn!(%mac, token = random_token, "downlink expired without TX_ACK");
+                            self.metrics
+                                .downlinks_pending
+                                .set(self.downlink_senders.len() as i64);
+                            lock(&self.stats).update(mac).ack_timeouts += 1;
+                            self.metrics.ack_timeouts.inc();
+                            let _ = ack_sender.send(Err(Error::AckTimeout));
+                            self.emit(Event::AckTimeout((mac, random_token))).await;
+                        }
                     }
                     InternalEvent::FailedSend((packet, mac)) => {
-                        self.clients.remove(&mac);
-                        self.client_tx_sender
-                            .send(Event::NoClientWithMac(packet, mac))
-                            .await?;
+                        self.metrics.downlink_send_failures.inc();
+                        info!(%mac, "evicting gateway after failed send");
+                        if let Some((ack_sender, _)) =
+                            self.downlink_senders.remove(mac, packet.random_token)
+                        {
+                            let _ = ack_sender.send(Err(Error::NotSent));
+                        }
+                        self.metrics
+                            .downlinks_pending
+                            .set(self.downlink_senders.len() as i64);
+                        if self.clients.remove(&mac).is_some() {
+                            self.forget(&mac);
+                            self.publish_gateways();
+                        }
+                        self.emit(Event::NoClientWithMac(packet, mac)).await;
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{pull_data, push_data, Down};
+    use pull_resp::{PhyData, Time};
+
+    // a runtime on a local socket, with the address gateways send to
+    async fn runtime(config: ServerConfig) -> (UdpRuntime, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        (
+            UdpRuntime::with_sockets(vec![socket], config).unwrap(),
+            addr,
+        )
+    }
+
+    async fn send(socket: &UdpSocket, packet: impl SerializablePacket, to: SocketAddr) {
+        let mut buf = [0u8; 1024];
+        let n = packet.serialize(&mut buf).unwrap() as usize;
+        socket.send_to(&buf[..n], to).await.unwrap();
+    }
+
+    // a gateway which has sent its PULL_DATA to `server`
+    async fn gateway(mac: MacAddress, server: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let pull_data = pull_data::Packet {
+            random_token: 1,
+            gateway_mac: mac,
+        };
+        send(&socket, pull_data, server).await;
+        socket
+    }
+
+    async fn recv(socket: &UdpSocket) -> Down {
+        recv_from(socket).await.0
+    }
+
+    // the frame with the server address it was sent from
+    async fn recv_from(socket: &UdpSocket) -> (Down, SocketAddr) {
+        let mut buf = [0u8; 1024];
+        let (n, src) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        (Packet::parse_downlink(&buf[..n]).unwrap(), src)
+    }
+
+    // a runtime listening on two local sockets, with their addresses
+    async fn runtime_on_two_sockets(config: ServerConfig) -> (UdpRuntime, [SocketAddr; 2]) {
+        let sockets = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let addrs = [
+            sockets[0].local_addr().unwrap(),
+            sockets[1].local_addr().unwrap(),
+        ];
+        (
+            UdpRuntime::with_sockets(sockets.into(), config).unwrap(),
+            addrs,
+        )
+    }
+
+    // PULL_DATA from each socket in turn, waiting for every PULL_ACK
+    async fn pull_data_from(gateways: &[(&UdpSocket, SocketAddr)], mac: MacAddress) {
+        for (gateway, server) in gateways {
+            let pull_data = pull_data::Packet {
+                random_token: 1,
+                gateway_mac: mac,
+            };
+            send(gateway, pull_data, *server).await;
+            assert!(matches!(recv(gateway).await, Down::PullAck(_)));
+        }
+        // the runtime handles the PULL_DATA after ACKing it
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    fn txpk() -> TxPk {
+        TxPk {
+            time: Time::immediate(),
+            freq: 902.8,
+            rfch: 0,
+            powe: 27,
+            modu: crate::Modulation::LORA,
+            datr: crate::DataRate::default(),
+            codr: Some(crate::CodingRate::_4_5),
+            ipol: true,
+            data: PhyData::new(vec![1, 2, 3, 4]),
+            fdev: None,
+            prea: None,
+            ncrc: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_an_error() {
+        let config: ServerConfig = serde_json::from_str("{\"internal_queue_size\":0}").unwrap();
+        let runtime = UdpRuntime::new_with_config("127.0.0.1:0", config).await;
+        assert!(matches!(runtime, Err(Error::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_unread_events() {
+        let config = ServerConfig::default()
+            .queue_sizes(4, 1)
+            .event_overflow(OverflowPolicy::Block);
+        let (runtime, addr) = runtime(config).await;
+        let (mut client_rx, mut client_tx, handle) = runtime.split_with_handle();
+        let mac = MacAddress::from([1; 8]);
+        let gateway = gateway(mac, addr).await;
+        assert!(matches!(client_rx.recv().await, Some(Event::NewClient(_))));
+
+        // a downlink which is never acknowledged
+        let downlink = tokio::spawn(async move { client_tx.send(txpk(), mac, None).await });
+        assert!(matches!(recv(&gateway).await, Down::PullAck(_)));
+        assert!(matches!(recv(&gateway).await, Down::PullResp(_)));
+
+        // uplinks which the client never reads
+        for _ in 0..20 {
+            let mut push_data = push_data::Packet::random();
+            push_data.gateway_mac = mac;
+            send(&gateway, push_data, addr).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        handle.shutdown();
+        let joined = timeout(Duration::from_secs(2), handle.join()).await;
+        assert!(matches!(joined, Ok(Ok(()))));
+        assert!(matches!(downlink.await.unwrap(), Err(Error::Shutdown)));
+    }
+
+    #[tokio::test]
+    async fn dropping_every_handle_stops_the_runtime() {
+        let (runtime, addr) = runtime(ServerConfig::default()).await;
+        let (client_rx, client_tx, handle) = runtime.split_with_handle();
+        let gateway = gateway(MacAddress::from([1; 8]), addr).await;
+        assert!(matches!(recv(&gateway).await, Down::PullAck(_)));
+
+        // a ClientTx can still send downlinks without a ClientRx
+        drop(client_rx);
+        let client_tx_clone = client_tx.clone();
+        drop(client_tx);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+
+        drop(client_tx_clone);
+        let joined = timeout(Duration::from_secs(1), handle.join()).await;
+        assert!(matches!(joined, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn oversized_downlink_fails_alone() {
+        let (runtime, addr) = runtime(ServerConfig::default().max_message_size(256)).await;
+        let (mut client_rx, mut client_tx, handle) = runtime.split_with_handle();
+        let mac = MacAddress::from([1; 8]);
+        let gateway = gateway(mac, addr).await;
+        assert!(matches!(client_rx.recv().await, Some(Event::NewClient(_))));
+        assert!(matches!(recv(&gateway).await, Down::PullAck(_)));
+
+        let oversized = TxPk {
+            data: PhyData::new(vec![0; 255]),
+            ..txpk()
+        };
+        let result = client_tx.send(oversized, mac, None).await;
+        assert!(matches!(result, Err(Error::SemtechUdp(_))));
+
+        // the runtime keeps serving other downlinks
+        let downlink = tokio::spawn(async move { client_tx.send(txpk(), mac, None).await });
+        assert!(matches!(recv(&gateway).await, Down::PullResp(_)));
+        assert!(!handle.is_finished());
+        downlink.abort();
+    }
+
+    #[tokio::test]
+    async fn gateways_are_watched_until_they_expire() {
+        let config = ServerConfig::default()
+            .disconnect_threshold(Some(Duration::from_millis(200)))
+            .cache_check_freq(Duration::from_millis(50));
+        let (runtime, addr) = runtime(config).await;
+        let mut watch = runtime.watch_gateways();
+        assert!(watch.borrow_and_update().is_empty());
+
+        let mac = MacAddress::from([1; 8]);
+        let gateway = gateway(mac, addr).await;
+        let gateway_addr = gateway.local_addr().unwrap();
+        timeout(Duration::from_secs(1), watch.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            watch.borrow_and_update().get(&mac).copied(),
+            Some(gateway_addr)
+        );
+        let info = runtime.gateway(mac).await.unwrap().unwrap();
+        assert_eq!(info.addr, gateway_addr);
+
+        // without further PULL_DATA the gateway is disconnected and leaves the registry
+        timeout(Duration::from_secs(1), watch.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(watch.borrow().is_empty());
+        assert!(runtime.gateways().await.unwrap().is_empty());
+        assert!(runtime.gateway_stats().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn metric_totals_outlive_the_gateway() {
+        let config = ServerConfig::default()
+            .disconnect_threshold(Some(Duration::from_millis(200)))
+            .cache_check_freq(Duration::from_millis(50));
+        let (runtime, addr) = runtime(config).await;
+        let (mut client_rx, client_tx) = runtime.split();
+        let mac = MacAddress::from([1; 8]);
+        let gateway = gateway(mac, addr).await;
+        assert!(matches!(client_rx.recv().await, Some(Event::NewClient(_))));
+        let mut push_data = push_data::Packet::random();
+        push_data.gateway_mac = mac;
+        send(&gateway, push_data, addr).await;
+        assert!(matches!(
+            client_rx.recv().await,
+            Some(Event::PacketReceived(..))
+        ));
+        let metrics = client_tx.render_metrics();
+        let sample =
+            format!("semtech_udp_server_gateway_rxpk_total{{gateway=\"{mac}\",crc=\"ok\"}} 1\n");
+        assert!(metrics.contains(&sample));
+
+        assert!(matches!(
+            client_rx.recv().await,
+            Some(Event::ClientDisconnected(_))
+        ));
+        let metrics = client_tx.render_metrics();
+        assert!(!metrics.contains("semtech_udp_server_gateway_rxpk_total{"));
+        assert!(metrics.contains("semtech_udp_server_rxpk_total{crc=\"ok\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn unsent_and_unacked_downlinks_fail_differently() {
+        let config = ServerConfig::default().pending_ack_expiry(Duration::from_millis(100));
+        let (runtime, addr) = runtime(config).await;
+        let (mut client_rx, mut client_tx) = runtime.split();
+
+        // never sent: an IPv6 gateway address is unreachable from the IPv4 socket
+        let unreachable = MacAddress::from([1; 8]);
+        client_tx
+            .sender
+            .send(InternalEvent::Client((
+                unreachable,
+                "[::1]:1700".parse().unwrap(),
+                2,
+                0,
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(client_rx.recv().await, Some(Event::NewClient(_))));
+        let result = client_tx.send(txpk(), unreachable, None).await;
+        assert!(matches!(result, Err(Error::NotSent)));
+
+        // sent, but the gateway never answers with a TX_ACK
+        let mac = MacAddress::from([2; 8]);
+        let gateway = gateway(mac, addr).await;
+        assert!(matches!(recv(&gateway).await, Down::PullAck(_)));
+        let result = client_tx.send(txpk(), mac, None).await;
+        assert!(matches!(recv(&gateway).await, Down::PullResp(_)));
+        assert!(matches!(result, Err(Error::AckTimeout)));
+    }
+
+    #[tokio::test]
+    async fn parse_failures_are_attributed_by_source_ip() {
+        let (runtime, addr) = runtime(ServerConfig::default()).await;
+        let (mut client_rx, client_tx) = runtime.split();
+        let mac = MacAddress::from([1; 8]);
+        let _gateway = gateway(mac, addr).await;
+        assert!(matches!(client_rx.recv().await, Some(Event::NewClient(_))));
+
+        // the up path, on another port of the same host
+        let up = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        up.send_to(&[2, 0, 0], addr).await.unwrap();
+        assert!(matches!(
+            client_rx.recv().await,
+            Some(Event::UnableToParseUdpFrame(..))
+        ));
+        let stats = client_tx.gateway_stats();
+        assert_eq!(stats[&mac].parse_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_gateways_are_reported_once_per_source() {
+        let known = MacAddress::from([1; 8]);
+        let config = ServerConfig::default().admission(Allowlist::default().allow(known));
+        let (runtime, addr) = runtime(config).await;
+        let (mut client_rx, _client_tx) = runtime.split();
+
+        let (spoofed, other) = (MacAddress::from([2; 8]), MacAddress::from([3; 8]));
+        let gateway = gateway(spoofed, addr).await;
+        for mac in [spoofed, spoofed, spoofed, other, other, known] {
+            let pull_data = pull_data::Packet {
+                random_token: 1,
+                gateway_mac: mac,
+            };
+            send(&gateway, pull_data, addr).await;
+        }
+
+        let mut unknown = Vec::new();
+        loop {
+            match timeout(Duration::from_secs(1), client_rx.recv()).await {
+                Ok(Some(Event::UnknownGateway((mac, _)))) => unknown.push(mac),
+                Ok(Some(Event::NewClient((mac, _)))) => {
+                    assert_eq!(mac, known);
+                    break;
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        assert_eq!(unknown, [spoofed, other]);
+    }
+
+    #[tokio::test]
+    async fn downlinks_follow_the_pull_data_socket() {
+        let (runtime, servers) = runtime_on_two_sockets(ServerConfig::default()).await;
+        let (_client_rx, client_tx) = runtime.split();
+        let mac = MacAddress::from([1; 8]);
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        for server in [servers[1], servers[0]] {
+            pull_data_from(&[(&gateway, server)], mac).await;
+            let mut downlink_tx = client_tx.clone();
+            tokio::spawn(async move { downlink_tx.send(txpk(), mac, None).await });
+            let (down, src) = recv_from(&gateway).await;
+            assert!(matches!(down, Down::PullResp(_)));
+            assert_eq!(src, server);
+        }
+    }
+
+    #[tokio::test]
+    async fn mac_conflicts_across_sockets() {
+        let mac = MacAddress::from([1; 8]);
+        let window = Duration::from_secs(60);
+
+        // the first address keeps the MAC and its socket
+        let config = ServerConfig::default().mac_conflict(MacConflictPolicy::FirstWins, window);
+        let (runtime, servers) = runtime_on_two_sockets(config).await;
+        let (mut client_rx, mut client_tx) = runtime.split();
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let claims = [
+            (&first, servers[0]),
+            (&second, servers[1]),
+            (&first, servers[0]),
+            (&second, servers[1]),
+        ];
+        pull_data_from(&claims, mac).await;
+        let mut conflict = None;
+        while let Ok(Some(event)) = timeout(Duration::from_millis(100), client_rx.recv()).await {
+            if let Event::MacConflict((_, addrs)) = event {
+                conflict = Some(addrs);
+            }
+        }
+        let first_addr = first.local_addr().unwrap();
+        assert_eq!(
+            conflict,
+            Some(vec![first_addr, second.local_addr().unwrap()])
+        );
+        let info = client_tx.gateway(mac).await.unwrap().unwrap();
+        assert_eq!(info.addr, first_addr);
+        tokio::spawn(async move { client_tx.send(txpk(), mac, None).await });
+        let (down, src) = recv_from(&first).await;
+        assert!(matches!(down, Down::PullResp(_)));
+        assert_eq!(src, servers[0]);
+
+        // the MAC is disconnected until the conflict ends
+        let config = ServerConfig::default().mac_conflict(MacConflictPolicy::Quarantine, window);
+        let (runtime, servers) = runtime_on_two_sockets(config).await;
+        let (_client_rx, mut client_tx) = runtime.split();
+        let claims = [
+            (&first, servers[0]),
+            (&second, servers[1]),
+            (&first, servers[0]),
+        ];
+        pull_data_from(&claims, mac).await;
+        assert!(client_tx.gateways().await.unwrap().is_empty());
+        let result = client_tx.send(txpk(), mac, None).await;
+        assert!(matches!(result, Err(Error::UnknownMac)));
+    }
+
+    #[cfg(feature = "lorawan")]
+    #[tokio::test]
+    async fn send_to_device_falls_back_to_the_next_gateway() {
+        use crate::lorawan::DevAddr;
+
+        let (runtime, addr) = runtime(ServerConfig::default()).await;
+        let (mut client_rx, mut client_tx) = runtime.split();
+        let (a, b, c) = (
+            MacAddress::from([1; 8]),
+            MacAddress::from([2; 8]),
+            MacAddress::from([3; 8]),
+        );
+        let gateway_a = gateway(a, addr).await;
+        let gateway_b = gateway(b, addr).await;
+        // c heard the device but never sent a PULL_DATA
+        let gateway_c = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for gateway in [&gateway_a, &gateway_b] {
+            assert!(matches!(recv(gateway).await, Down::PullAck(_)));
+        }
+
+        // an unconfirmed uplink from DevAddr 01020304, best heard by c, then a, then b
+        for (gateway, mac, snr, tmst) in [
+            (&gateway_c, c, 10.0, 3_000),
+            (&gateway_a, a, 5.0, 1_000),
+            (&gateway_b, b, 0.0, 2_000),
+        ] {
+            let mut push_data = push_data::Packet::random();
+            push_data.gateway_mac = mac;
+            if let Some(RxPk::V1(rxpk)) = push_data
+                .data
+                .rxpk
+                .as_mut()
+                .and_then(|rxpk| rxpk.first_mut())
+            {
+                rxpk.data = vec![0x40, 4, 3, 2, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+                rxpk.lsnr = snr;
+                rxpk.tmst = tmst;
+            }
+            send(gateway, push_data, addr).await;
+            assert!(matches!(recv(gateway).await, Down::PushAck(_)));
+        }
+        let mut uplinks = 0;
+        while uplinks < 3 {
+            if let Some(Event::PacketReceived(..)) = client_rx.recv().await {
+                uplinks += 1;
+            }
+        }
+
+        let device = DeviceKey::DevAddr(DevAddr(0x0102_0304));
+        let sent = tokio::spawn(async move {
+            let txpk = |candidate: &Candidate| TxPk {
+                time: Time::by_tmst(candidate.tmst.wrapping_add(1_000_000)),
+                ..txpk()
+            };
+            client_tx
+                .send_to_device(device, txpk, GatewaySelection::BestSnr, None)
+                .await
+        });
+
+        // c is not connected, a answers too late, b transmits; each on its own clock
+        for (gateway, mac, tmst, nack) in [
+            (&gateway_a, a, 1_000, Some(crate::tx_ack::Error::TooLate)),
+            (&gateway_b, b, 2_000, None),
+        ] {
+            let Down::PullResp(pull_resp) = recv(gateway).await else {
+                panic!("expected a PULL_RESP");
+            };
+            assert_eq!(pull_resp.data.txpk.time.tmst(), Some(tmst + 1_000_000));
+            let tx_ack = match nack {
+                Some(error) => pull_resp.into_nack_with_error_for_gateway(error, mac),
+                None => pull_resp.into_ack_for_gateway(mac),
+            };
+            send(gateway, tx_ack, addr).await;
+        }
+        assert!(matches!(sent.await.unwrap(), Ok((mac, _)) if mac == b));
+    }
+}
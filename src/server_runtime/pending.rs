@@ -0,0 +1,192 @@
+/*
+   Downlinks sent to gateways and awaiting their TX_ACK, keyed by gateway MAC and token.
+   Tokens are allocated per gateway so that two downlinks in flight to the same gateway
+   never share one. Expired downlinks are remembered for a while so that a late TX_ACK can
+   be told apart from one for an unknown token.
+*/
+use super::{AckSender, MacAddress};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// a TX_ACK which answers no downlink pending for its gateway
+pub(crate) enum UnmatchedAck {
+    // the token is awaited from another gateway
+    Mismatched(MacAddress),
+    // with when the gateway's expired downlink it answers was sent, if any
+    Unmatched(Option<Instant>),
+}
+
+pub(crate) struct PendingDownlinks {
+    gateways: HashMap<MacAddress, HashMap<u16, (AckSender, Instant)>>,
+    // time each expired downlink was sent, kept for `expired_retention` after it expired
+    expired: HashMap<(MacAddress, u16), (Instant, Instant)>,
+    expired_retention: Duration,
+}
+
+impl PendingDownlinks {
+    pub fn new(expired_retention: Duration) -> PendingDownlinks {
+        PendingDownlinks {
+            gateways: HashMap::new(),
+            expired: HashMap::new(),
+            expired_retention,
+        }
+    }
+
+    // registers the downlink's waiter under a token free for the gateway and returns the token
+    pub fn insert(&mut self, mac: MacAddress, ack_sender: AckSender, now: Instant) -> u16 {
+        let token = self.allocate(mac, rand::thread_rng().gen());
+        self.gateways
+            .entry(mac)
+            .or_default()
+            .insert(token, (ack_sender, now));
+        token
+    }
+
+    // first token from `start` neither in flight to the gateway nor recently expired
+    fn allocate(&self, mac: MacAddress, start: u16) -> u16 {
+        let in_flight = self.gateways.get(&mac);
+        (0..=u16::MAX)
+            .map(|offset| start.wrapping_add(offset))
+            .find(|token| {
+                !in_flight.is_some_and(|tokens| tokens.contains_key(token))
+                    && !self.expired.contains_key(&(mac, *token))
+            })
+            .unwrap_or(start)
+    }
+
+    pub fn remove(&mut self, mac: MacAddress, token: u16) -> Option<(AckSender, Instant)> {
+        let tokens = self.gateways.get_mut(&mac)?;
+        let pending = tokens.remove(&token);
+        if tokens.is_empty() {
+            self.gateways.remove(&mac);
+        }
+        pending
+    }
+
+    // removes the downlink only if it is still the one registered at `sent`
+    pub fn expire(
+        &mut self,
+        mac: MacAddress,
+        token: u16,
+        sent: Instant,
+        now: Instant,
+    ) -> Option<AckSender> {
+        match self.gateways.get(&mac)?.get(&token) {
+            Some((_, registered)) if *registered == sent => {
+                self.expired.insert((mac, token), (sent, now));
+                self.remove(mac, token).map(|(ack_sender, _)| ack_sender)
+            }
+            _ => None,
+        }
+    }
+
+    // tokens are per gateway, so the gateway's own expired downlinks are checked first
+    pub fn unmatched(&mut self, mac: MacAddress, token: u16) -> UnmatchedAck {
+        if let Some(sent) = self.take_expired(mac, token) {
+            UnmatchedAck::Unmatched(Some(sent))
+        } else if let Some(expected) = self.gateway_with_token(token) {
+            UnmatchedAck::Mismatched(expected)
+        } else {
+            UnmatchedAck::Unmatched(None)
+        }
+    }
+
+    // when the expired downlink a late TX_ACK belongs to was sent
+    fn take_expired(&mut self, mac: MacAddress, token: u16) -> Option<Instant> {
+        self.expired.remove(&(mac, token)).map(|(sent, _)| sent)
+    }
+
+    pub fn forget_expired(&mut self, now: Instant) {
+        let retention = self.expired_retention;
+        self.expired
+            .retain(|_, (_, expired)| now.duration_since(*expired) < retention);
+    }
+
+    // another gateway awaiting a TX_ACK with the token
+    fn gateway_with_token(&self, token: u16) -> Option<MacAddress> {
+        self.gateways
+            .iter()
+            .find(|(_, tokens)| tokens.contains_key(&token))
+            .map(|(mac, _)| *mac)
+    }
+
+    // load of a gateway, for GatewaySelection::LeastLoaded
+    #[cfg(feature = "lorawan")]
+    pub fn count(&self, mac: &MacAddress) -> usize {
+        self.gateways.get(mac).map_or(0, HashMap::len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.gateways.values().map(HashMap::len).sum()
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = AckSender> + '_ {
+        self.gateways
+            .drain()
+            .flat_map(|(_, tokens)| tokens.into_values().map(|(ack_sender, _)| ack_sender))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn tokens_are_per_gateway() {
+        let (a, b) = (MacAddress::from([1; 8]), MacAddress::from([2; 8]));
+        let now = Instant::now();
+        let mut pending = PendingDownlinks::new(Duration::from_secs(1));
+        let first = pending.insert(a, oneshot::channel().0, now);
+        assert_eq!(pending.allocate(a, first), first.wrapping_add(1));
+        assert_eq!(pending.allocate(b, first), first);
+
+        assert!(pending.remove(b, first).is_none());
+        assert_eq!(pending.gateway_with_token(first), Some(a));
+        assert_eq!(pending.len(), 1);
+
+        let later = now + Duration::from_secs(1);
+        assert!(pending.expire(a, first, later, later).is_none());
+        assert!(pending.expire(a, first, now, later).is_some());
+        assert_eq!(pending.len(), 0);
+
+        pending.forget_expired(later);
+        assert_eq!(pending.take_expired(a, first), Some(now));
+        assert_eq!(pending.take_expired(a, first), None);
+        let second = pending.insert(a, oneshot::channel().0, now);
+        pending.expire(a, second, now, now);
+        assert_eq!(pending.allocate(a, second), second.wrapping_add(1));
+        pending.forget_expired(later);
+        assert!(pending.expired.is_empty());
+    }
+
+    #[test]
+    fn late_tx_ack_with_a_token_in_flight_elsewhere() {
+        let (a, b) = (MacAddress::from([1; 8]), MacAddress::from([2; 8]));
+        let now = Instant::now();
+        let mut pending = PendingDownlinks::new(Duration::from_secs(1));
+        let token = pending.insert(b, oneshot::channel().0, now);
+        pending.expire(b, token, now, now);
+        pending
+            .gateways
+            .entry(a)
+            .or_default()
+            .insert(token, (oneshot::channel().0, now));
+
+        assert!(matches!(
+            pending.unmatched(b, token),
+            UnmatchedAck::Unmatched(Some(sent)) if sent == now
+        ));
+        assert!(matches!(
+            pending.unmatched(b, token),
+            UnmatchedAck::Mismatched(expected) if expected == a
+        ));
+        assert!(matches!(
+            pending.unmatched(a, token.wrapping_add(1)),
+            UnmatchedAck::Unmatched(None)
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+/*
+   Token buckets limiting how many frames UdpRx processes per source address and per
+   gateway, and how many parse errors it reports, so that a flood cannot saturate the
+   internal and client queues.
+*/
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+// idle buckets are forgotten at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sustained rate and burst size of a token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> RateLimit {
+        RateLimit { per_second, burst }
+    }
+
+    // a bucket which never refills, or never holds a token, would drop every frame
+    pub(crate) fn is_valid(&self) -> bool {
+        self.per_second > 0.0 && self.burst > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+pub(crate) struct RateLimiter<K> {
+    limit: RateLimit,
+    buckets: HashMap<K, TokenBucket>,
+    pruned: Instant,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: RateLimit, now: Instant) -> RateLimiter<K> {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+            pruned: now,
+        }
+    }
+
+    // takes a token from the key's bucket; false when it is empty
+    pub fn check(&mut self, key: K, now: Instant) -> bool {
+        if now.duration_since(self.pruned) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+        let limit = self.limit;
+        let bucket = self.buckets.entry(key).or_insert(TokenBucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // buckets which refilled completely behave as new ones
+    fn prune(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst as f64
+        });
+        self.pruned = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimit::new(2.0, 3), now);
+        assert!((0..3).all(|_| limiter.check("a", now)));
+        assert!(!limiter.check("a", now));
+        assert!(limiter.check("b", now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check("a", later));
+        assert!(!limiter.check("a", later));
+
+        limiter.prune(later + Duration::from_secs(2));
+        assert!(limiter.buckets.is_empty());
+    }
+}
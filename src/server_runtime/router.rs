@@ -0,0 +1,302 @@
+/*
+   Routes server events to per-backend receivers based on the LoRaWAN header of each uplink:
+   data frames by DevAddr (or the NetID prefix of the DevAddr), join requests by JoinEUI.
+
+   Routes are matched in registration order and the first match wins. Deduplicated uplinks are
+   routed by their payload, late duplicates by their rxpk. Events which are not uplinks
+   (stats, client changes, parse errors...) are delivered to the first catch-all route.
+*/
+use super::{ClientRx, Event};
+use crate::lorawan::{DevAddr, Eui64, MacPayload, PhyPayload};
+use std::ops::RangeInclusive;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+
+/// NwkID length in bits for each NetID type
+const NWK_ID_BITS: [u32; 8] = [6, 6, 9, 11, 12, 13, 15, 17];
+
+/// The leading `len` bits of a DevAddr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DevAddrPrefix {
+    prefix: u32,
+    len: u8,
+}
+
+impl DevAddrPrefix {
+    pub fn new(prefix: u32, len: u8) -> DevAddrPrefix {
+        let len = len.min(32);
+        DevAddrPrefix {
+            prefix: prefix & Self::mask(len),
+            len,
+        }
+    }
+
+    /// The DevAddr prefix assigned to a NetID: the type prefix followed by the NwkID
+    pub fn from_net_id(net_id: u32) -> DevAddrPrefix {
+        let net_type = (net_id >> 21) & 0x07;
+        let nwk_id_bits = NWK_ID_BITS[net_type as usize];
+        let type_bits = net_type + 1;
+        // type N is encoded as N ones followed by a zero
+        let type_prefix = (1 << type_bits) - 2;
+        let nwk_id = net_id & ((1 << nwk_id_bits) - 1);
+        let len = type_bits + nwk_id_bits;
+        DevAddrPrefix::new(
+            ((type_prefix << nwk_id_bits) | nwk_id) << (32 - len),
+            len as u8,
+        )
+    }
+
+    fn mask(len: u8) -> u32 {
+        if len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - len as u32)
+        }
+    }
+
+    pub fn prefix(&self) -> u32 {
+        self.prefix
+    }
+
+    pub fn len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, dev_addr: DevAddr) -> bool {
+        dev_addr.0 & Self::mask(self.len) == self.prefix
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    DevAddrPrefix(DevAddrPrefix),
+    DevAddrRange(RangeInclusive<DevAddr>),
+    JoinEuiRange(RangeInclusive<Eui64>),
+    CatchAll,
+}
+
+impl Route {
+    pub fn net_id(net_id: u32) -> Route {
+        Route::DevAddrPrefix(DevAddrPrefix::from_net_id(net_id))
+    }
+
+    fn matches(&self, key: &Option<RouteKey>) -> bool {
+        match (self, key) {
+            (Route::CatchAll, _) => true,
+            (Route::DevAddrPrefix(prefix), Some(RouteKey::DevAddr(dev_addr))) => {
+                prefix.contains(*dev_addr)
+            }
+            (Route::DevAddrRange(range), Some(RouteKey::DevAddr(dev_addr))) => {
+                range.contains(dev_addr)
+            }
+            (Route::JoinEuiRange(range), Some(RouteKey::JoinEui(join_eui))) => {
+                range.contains(join_eui)
+            }
+            _ => false,
+        }
+    }
+}
+
+enum RouteKey {
+    DevAddr(DevAddr),
+    JoinEui(Eui64),
+}
+
+fn route_key(payload: &[u8]) -> Option<RouteKey> {
+    match PhyPayload::parse(payload).ok()?.payload() {
+        MacPayload::Data(header) => Some(RouteKey::DevAddr(header.dev_addr)),
+        MacPayload::JoinRequest(join_request) => Some(RouteKey::JoinEui(join_request.join_eui)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RouterCounters {
+    routed: AtomicU64,
+    unrouted: AtomicU64,
+    unparsable: AtomicU64,
+    dropped_events: AtomicU64,
+}
+
+impl RouterCounters {
+    /// Uplinks delivered to a route
+    pub fn routed(&self) -> u64 {
+        self.routed.load(Ordering::Relaxed)
+    }
+
+    /// Uplinks which matched no route
+    pub fn unrouted(&self) -> u64 {
+        self.unrouted.load(Ordering::Relaxed)
+    }
+
+    /// Uplinks whose PHYPayload could not be parsed or carried no routing key;
+    /// these can only be delivered to a catch-all route
+    pub fn unparsable(&self) -> u64 {
+        self.unparsable.load(Ordering::Relaxed)
+    }
+
+    /// Non-uplink events dropped because there is no catch-all route
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+}
+
+pub type RouteRx = mpsc::Receiver<Event>;
+
+#[derive(Debug, Default)]
+pub struct Router {
+    routes: Vec<(Route, mpsc::Sender<Event>)>,
+    counters: Arc<RouterCounters>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers a route and returns the receiver for its events
+    pub fn add_route(&mut self, route: Route, buffer: usize) -> RouteRx {
+        let (sender, receiver) = mpsc::channel(buffer);
+        self.routes.push((route, sender));
+        receiver
+    }
+
+    pub fn counters(&self) -> Arc<RouterCounters> {
+        self.counters.clone()
+    }
+
+    /// Delivers one event to the matching route. Routes whose receiver has been dropped
+    /// are removed.
+    pub async fn route(&mut self, event: Event) {
+        let payload = match &event {
+            Event::PacketReceived(rxpk, _) | Event::LateDuplicate(rxpk, _) => Some(rxpk.data()),
+            Event::DedupedPacketReceived(packet) => Some(&packet.payload),
+            _ => None,
+        };
+        let index = match payload {
+            Some(payload) => {
+                let key = route_key(payload);
+                if key.is_none() {
+                    self.counters.unparsable.fetch_add(1, Ordering::Relaxed);
+                }
+                let index = self
+                    .routes
+                    .iter()
+                    .position(|(route, _)| route.matches(&key));
+                let counter = if index.is_some() {
+                    &self.counters.routed
+                } else {
+                    &self.counters.unrouted
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                index
+            }
+            None => {
+                let index = self
+                    .routes
+                    .iter()
+                    .position(|(route, _)| *route == Route::CatchAll);
+                if index.is_none() {
+                    self.counters.dropped_events.fetch_add(1, Ordering::Relaxed);
+                }
+                index
+            }
+        };
+
+        if let Some(index) = index {
+            if self.routes[index].1.send(event).await.is_err() {
+                self.routes.remove(index);
+            }
+        }
+    }
+
+    /// Consumes events from the server runtime until it stops or every route has been dropped
+    pub async fn run(mut self, mut client_rx: ClientRx) {
+        while !self.routes.is_empty() {
+            match client_rx.recv().await {
+                Some(event) => self.route(event).await,
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn net_id_prefixes() {
+        // type 0 NetIDs take a 7 bit prefix
+        let prefix = DevAddrPrefix::from_net_id(0x00_0024);
+        assert_eq!((prefix.prefix(), prefix.len()), (0x4800_0000, 7));
+        assert!(prefix.contains(DevAddr(0x4800_0001)));
+        assert!(prefix.contains(DevAddr(0x49FF_FFFF)));
+        assert!(!prefix.contains(DevAddr(0x4A00_0000)));
+
+        // type 3 NetIDs take a 4 bit type prefix and an 11 bit NwkID
+        let prefix = DevAddrPrefix::from_net_id(0x60_002D);
+        assert_eq!((prefix.prefix(), prefix.len()), (0xE05A_0000, 15));
+        assert!(prefix.contains(DevAddr(0xE05B_FFFF)));
+        assert!(!prefix.contains(DevAddr(0xE05C_0000)));
+    }
+
+    #[test]
+    fn route_matching() {
+        let dev_addr = Some(RouteKey::DevAddr(DevAddr(0x4800_0010)));
+        let join_eui = Some(RouteKey::JoinEui(Eui64(0x10)));
+        assert!(Route::net_id(0x24).matches(&dev_addr));
+        assert!(!Route::net_id(0x24).matches(&join_eui));
+        assert!(
+            Route::DevAddrRange(DevAddr(0x4800_0000)..=DevAddr(0x4800_00FF)).matches(&dev_addr)
+        );
+        assert!(Route::JoinEuiRange(Eui64(0)..=Eui64(0xFF)).matches(&join_eui));
+        assert!(!Route::JoinEuiRange(Eui64(0)..=Eui64(0xFF)).matches(&None));
+        assert!(Route::CatchAll.matches(&None));
+        assert!(DevAddrPrefix::new(0, 0).contains(DevAddr(u32::MAX)));
+    }
+
+    #[tokio::test]
+    async fn deduped_uplinks_are_routed() {
+        use crate::push_data::{self, RxPk};
+        use crate::server_runtime::{DedupedPacket, UplinkCopy};
+        use crate::MacAddress;
+
+        // an unconfirmed data uplink from DevAddr 01AFB92E
+        let payload =
+            hex::decode("402eb9af0100e30f02687ecbc867ffdfe771ceb5e491f12c4427176c53").unwrap();
+        let mut rxpk = push_data::Packet::random().data.rxpk.unwrap().remove(0);
+        if let RxPk::V1(rxpk) = &mut rxpk {
+            rxpk.data = payload.clone();
+        }
+        let gateway_mac = MacAddress::from([1; 8]);
+
+        let mut router = Router::new();
+        let mut routed = router.add_route(Route::DevAddrRange(DevAddr(0)..=DevAddr(u32::MAX)), 4);
+        router
+            .route(Event::DedupedPacketReceived(DedupedPacket {
+                payload,
+                copies: vec![UplinkCopy {
+                    gateway_mac,
+                    rxpk: rxpk.clone(),
+                }],
+            }))
+            .await;
+        router.route(Event::LateDuplicate(rxpk, gateway_mac)).await;
+
+        assert!(matches!(
+            routed.try_recv(),
+            Ok(Event::DedupedPacketReceived(_))
+        ));
+        assert!(matches!(routed.try_recv(), Ok(Event::LateDuplicate(..))));
+        let counters = router.counters();
+        assert_eq!((counters.routed(), counters.dropped_events()), (2, 0));
+    }
+}
@@ -0,0 +1,199 @@
+/*
+   Tracks which gateways recently heard each device so that downlinks can be sent
+   without the caller choosing a gateway. The concentrator timestamp of each gateway's
+   latest uplink is kept, since a downlink scheduled by `tmst` is only valid on the
+   clock of the gateway it is sent through.
+*/
+use super::{MacAddress, RxPk};
+use crate::lorawan::{DevAddr, Eui64, MacPayload, PhyPayload};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceKey {
+    DevAddr(DevAddr),
+    // devices which have not joined yet are known by their DevEUI
+    DevEui(Eui64),
+}
+
+impl DeviceKey {
+    pub fn from_rxpk(rxpk: &RxPk) -> Option<DeviceKey> {
+        match PhyPayload::parse(rxpk.data()).ok()?.payload() {
+            MacPayload::Data(header) => Some(DeviceKey::DevAddr(header.dev_addr)),
+            MacPayload::JoinRequest(join_request) => Some(DeviceKey::DevEui(join_request.dev_eui)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewaySelection {
+    BestSnr,
+    BestRssi,
+    // fewest downlinks awaiting a TX_ACK, ties broken by SNR
+    LeastLoaded,
+    // gateways which NACKed within the duration are tried last, otherwise by SNR
+    AvoidRecentNack(Duration),
+}
+
+/// A gateway which recently heard the device, with the `tmst` of its latest uplink
+/// on that gateway's concentrator clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub gateway_mac: MacAddress,
+    pub tmst: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Observation {
+    snr: f32,
+    rssi: i32,
+    tmst: u32,
+    seen: Instant,
+}
+
+pub(crate) struct UplinkHistory {
+    retention: Duration,
+    devices: HashMap<DeviceKey, HashMap<MacAddress, Observation>>,
+}
+
+impl UplinkHistory {
+    pub fn new(retention: Duration) -> UplinkHistory {
+        UplinkHistory {
+            retention,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, rxpk: &RxPk, mac: MacAddress, now: Instant) {
+        if let Some(key) = DeviceKey::from_rxpk(rxpk) {
+            self.devices.entry(key).or_default().insert(
+                mac,
+                Observation {
+                    snr: rxpk.snr(),
+                    rssi: rxpk.signal_rssi().unwrap_or_else(|| rxpk.channel_rssi()),
+                    tmst: rxpk.timestamp(),
+                    seen: now,
+                },
+            );
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let retention = self.retention;
+        self.devices.retain(|_, gateways| {
+            gateways.retain(|_, observation| now.duration_since(observation.seen) < retention);
+            !gateways.is_empty()
+        });
+    }
+
+    /// Gateways which heard the device within the retention period, best first
+    pub fn candidates<L, N>(
+        &self,
+        device: &DeviceKey,
+        selection: GatewaySelection,
+        now: Instant,
+        load: L,
+        last_nack: N,
+    ) -> Vec<Candidate>
+    where
+        L: Fn(&MacAddress) -> usize,
+        N: Fn(&MacAddress) -> Option<Instant>,
+    {
+        let mut gateways: Vec<(MacAddress, Observation)> = match self.devices.get(device) {
+            Some(gateways) => gateways
+                .iter()
+                .filter(|(_, observation)| now.duration_since(observation.seen) < self.retention)
+                .map(|(mac, observation)| (*mac, *observation))
+                .collect(),
+            None => return Vec::new(),
+        };
+
+        let by_snr = |a: &Observation, b: &Observation| b.snr.total_cmp(&a.snr);
+        gateways.sort_by(|(mac_a, a), (mac_b, b)| match selection {
+            GatewaySelection::BestSnr => by_snr(a, b),
+            GatewaySelection::BestRssi => b.rssi.cmp(&a.rssi),
+            GatewaySelection::LeastLoaded => {
+                load(mac_a).cmp(&load(mac_b)).then_with(|| by_snr(a, b))
+            }
+            GatewaySelection::AvoidRecentNack(period) => {
+                let recently_nacked = |mac: &MacAddress| {
+                    last_nack(mac).is_some_and(|nack| now.duration_since(nack) < period)
+                };
+                match (recently_nacked(mac_a), recently_nacked(mac_b)) {
+                    (false, true) => Ordering::Less,
+                    (true, false) => Ordering::Greater,
+                    _ => by_snr(a, b),
+                }
+            }
+        });
+        gateways
+            .into_iter()
+            .map(|(gateway_mac, observation)| Candidate {
+                gateway_mac,
+                tmst: observation.tmst,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::push_data::{Packet, RxPk};
+
+    fn rxpk(snr: f32, rssi: i32) -> RxPk {
+        let mut rxpk = Packet::random().data.rxpk.unwrap().pop().unwrap();
+        if let RxPk::V1(pk) = &mut rxpk {
+            // unconfirmed uplink from DevAddr 01020304
+            pk.data = vec![0x40, 4, 3, 2, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+            pk.lsnr = snr;
+            pk.rssis = Some(rssi);
+        }
+        rxpk
+    }
+
+    #[test]
+    fn orders_candidates_by_policy() {
+        let now = Instant::now();
+        let (a, b, c) = (
+            MacAddress::from([1; 8]),
+            MacAddress::from([2; 8]),
+            MacAddress::from([3; 8]),
+        );
+        let mut history = UplinkHistory::new(Duration::from_secs(10));
+        history.record(&rxpk(5.0, -110), a, now);
+        history.record(&rxpk(-2.0, -90), b, now);
+        history.record(&rxpk(0.0, -100), c, now);
+
+        let device = DeviceKey::DevAddr(DevAddr(0x0102_0304));
+        let candidates = |selection| {
+            history
+                .candidates(
+                    &device,
+                    selection,
+                    now,
+                    |mac| if *mac == a { 3 } else { 0 },
+                    |mac| if *mac == c { Some(now) } else { None },
+                )
+                .into_iter()
+                .map(|candidate| candidate.gateway_mac)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(candidates(GatewaySelection::BestSnr), vec![a, c, b]);
+        assert_eq!(candidates(GatewaySelection::BestRssi), vec![b, c, a]);
+        assert_eq!(candidates(GatewaySelection::LeastLoaded), vec![c, b, a]);
+        assert_eq!(
+            candidates(GatewaySelection::AvoidRecentNack(Duration::from_secs(1))),
+            vec![a, b, c]
+        );
+
+        history.expire(now + Duration::from_secs(10));
+        assert!(history
+            .candidates(&device, GatewaySelection::BestSnr, now, |_| 0, |_| None)
+            .is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use super::{MacAddress, RxPk};
+use crate::{push_data::CRC, tx_ack};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the TX_ACK latency buckets, in milliseconds
+pub const ACK_LATENCY_BUCKETS_MS: [u64; 8] = [10, 25, 50, 100, 250, 500, 1000, 2500];
+
+// Time between sending a PULL_RESP and receiving its TX_ACK
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AckLatency {
+    /// Number of ACKs per bucket of `ACK_LATENCY_BUCKETS_MS`; the last entry counts
+    /// the ACKs slower than every bucket. Buckets are not cumulative.
+    pub buckets: [u64; ACK_LATENCY_BUCKETS_MS.len() + 1],
+    pub count: u64,
+    pub sum_ms: u64,
+}
+
+// index of the ACK latency bucket counting `ms`
+pub(crate) fn latency_bucket(ms: u64) -> usize {
+    ACK_LATENCY_BUCKETS_MS
+        .iter()
+        .position(|bound| ms <= *bound)
+        .unwrap_or(ACK_LATENCY_BUCKETS_MS.len())
+}
+
+impl AckLatency {
+    fn observe(&mut self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        self.buckets[latency_bucket(ms)] += 1;
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+}
+
+// TX_ACK results, by tx_ack::Error variant
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxAckCounts {
+    pub ok: u64,
+    pub too_late: u64,
+    pub too_early: u64,
+    pub collision_packet: u64,
+    pub collision_beacon: u64,
+    pub tx_freq: u64,
+    pub tx_power: u64,
+    pub adjusted_tx_power: u64,
+    pub gps_unlocked: u64,
+    pub send_lbt: u64,
+    pub send_fail: u64,
+}
+
+impl TxAckCounts {
+    fn record(&mut self, result: &Result<Option<u32>, tx_ack::Error>) {
+        let counter = match result {
+            Ok(_) => &mut self.ok,
+            Err(tx_ack::Error::TooLate) => &mut self.too_late,
+            Err(tx_ack::Error::TooEarly) => &mut self.too_early,
+            Err(tx_ack::Error::CollisionPacket) => &mut self.collision_packet,
+            Err(tx_ack::Error::CollisionBeacon) => &mut self.collision_beacon,
+            Err(tx_ack::Error::InvalidTransmitFrequency) => &mut self.tx_freq,
+            Err(tx_ack::Error::InvalidTransmitPower(_)) => &mut self.tx_power,
+            Err(tx_ack::Error::AdjustedTransmitPower(_, _)) => &mut self.adjusted_tx_power,
+            Err(tx_ack::Error::GpsUnlocked) => &mut self.gps_unlocked,
+            Err(tx_ack::Error::SendLBT) => &mut self.send_lbt,
+            Err(tx_ack::Error::SendFail) => &mut self.send_fail,
+        };
+        *counter += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.ok
+            + self.too_late
+            + self.too_early
+            + self.collision_packet
+            + self.collision_beacon
+            + self.tx_freq
+            + self.tx_power
+            + self.adjusted_tx_power
+            + self.gps_unlocked
+            + self.send_lbt
+            + self.send_fail
+    }
+}
+
+// Counters kept by the server runtime for each gateway since it was first heard
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GatewayStats {
+    pub push_data: u64,
+    pub rxpk_crc_ok: u64,
+    pub rxpk_crc_fail: u64,
+    pub rxpk_no_crc: u64,
+    pub stat: u64,
+    pub pull_data: u64,
+    pub downlinks_sent: u64,
+    pub tx_ack: TxAckCounts,
+    pub ack_latency: AckLatency,
+    // downlinks sent which expired without a TX_ACK
+    pub ack_timeouts: u64,
+    // frames from the gateway's IP which could not be parsed, when no other connected
+    // gateway shares that IP
+    pub parse_failures: u64,
+}
+
+impl GatewayStats {
+    pub(crate) fn rxpk(&mut self, rxpk: &RxPk) {
+        match rxpk.crc_status() {
+            CRC::OK => self.rxpk_crc_ok += 1,
+            CRC::Fail => self.rxpk_crc_fail += 1,
+            CRC::Disabled => self.rxpk_no_crc += 1,
+        }
+    }
+
+    pub(crate) fn tx_ack(
+        &mut self,
+        result: &Result<Option<u32>, tx_ack::Error>,
+        latency: Duration,
+    ) {
+        self.tx_ack.record(result);
+        self.ack_latency.observe(latency);
+    }
+}
+
+// How long the stats of a gateway which is not connected are kept after their last update,
+// when the runtime has no disconnect threshold
+pub(crate) const STATS_RETENTION: Duration = Duration::from_secs(60);
+
+// GatewayStats of each MAC heard, with the time they were last updated
+#[derive(Debug, Default)]
+pub(crate) struct StatsTable {
+    stats: HashMap<MacAddress, (GatewayStats, Instant)>,
+}
+
+impl StatsTable {
+    pub fn update(&mut self, mac: MacAddress) -> &mut GatewayStats {
+        let now = Instant::now();
+        let (stats, updated) = self
+            .stats
+            .entry(mac)
+            .or_insert_with(|| (GatewayStats::default(), now));
+        *updated = now;
+        stats
+    }
+
+    pub fn remove(&mut self, mac: &MacAddress) {
+        self.stats.remove(mac);
+    }
+
+    // forgets the MACs not kept whose stats were last updated `retention` ago or more
+    pub fn expire(
+        &mut self,
+        now: Instant,
+        retention: Duration,
+        keep: impl Fn(&MacAddress) -> bool,
+    ) {
+        self.stats.retain(|mac, (_, updated)| {
+            keep(mac) || now.saturating_duration_since(*updated) < retention
+        });
+    }
+
+    pub fn snapshot(&self) -> HashMap<MacAddress, GatewayStats> {
+        self.stats
+            .iter()
+            .map(|(mac, (stats, _))| (*mac, stats.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tx_ack_outcomes() {
+        let mut stats = GatewayStats::default();
+        stats.tx_ack(&Ok(None), Duration::from_millis(10));
+        stats.tx_ack(&Err(tx_ack::Error::TooLate), Duration::from_millis(11));
+        stats.tx_ack(&Err(tx_ack::Error::TooLate), Duration::from_secs(3));
+        assert_eq!(stats.tx_ack.ok, 1);
+        assert_eq!(stats.tx_ack.too_late, 2);
+        assert_eq!(stats.tx_ack.total(), 3);
+        assert_eq!(stats.ack_latency.buckets, [1, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.ack_latency.count, 3);
+        assert_eq!(stats.ack_latency.sum_ms, 3021);
+    }
+
+    #[test]
+    fn idle_stats_expire_unless_kept() {
+        let mut table = StatsTable::default();
+        let (connected, gone) = (MacAddress::from([1; 8]), MacAddress::from([2; 8]));
+        table.update(connected).pull_data += 1;
+        table.update(gone).push_data += 1;
+
+        let later = Instant::now() + Duration::from_secs(2);
+        table.expire(later, Duration::from_secs(5), |_| false);
+        assert_eq!(table.snapshot().len(), 2);
+        table.expire(later, Duration::from_secs(1), |mac| *mac == connected);
+        assert_eq!(table.snapshot().keys().collect::<Vec<_>>(), [&connected]);
+
+        table.remove(&connected);
+        assert!(table.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,7 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// the runtimes never hold a lock across an await or a panic, so a poisoned lock is
+// recovered rather than propagated
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,35 @@
+/*
+   Wrappers around the `tracing` macros, in textual scope for the runtimes via #[macro_use].
+   Without the `tracing` feature they expand to nothing and their arguments are not evaluated,
+   so values only logged are named with a leading underscore.
+*/
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)*) => {
+        tracing::$level!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)*) => {
+        ()
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { trace_event!(debug, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { trace_event!(info, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { trace_event!(warn, $($arg)*) };
+}
+
+#[allow(unused_macros)] // not used by the client runtime
+macro_rules! error {
+    ($($arg:tt)*) => { trace_event!(error, $($arg)*) };
+}